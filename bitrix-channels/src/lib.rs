@@ -1,5 +1,6 @@
 use hmac::{Hmac, Mac};
 use sha1::Sha1;
+use std::fmt;
 use thiserror::Error;
 
 #[allow(dead_code)]
@@ -17,6 +18,10 @@ pub enum ParseError {
     EmptyChannels(String),
     #[error("Channel strings is empty")]
     EmptyString,
+    #[error("Channel id bytes is empty")]
+    EmptyBytes,
+    #[error("Signature mismatch for channel {0}")]
+    SignatureMismatch(String),
 }
 
 impl Parser {
@@ -94,6 +99,69 @@ impl Parser {
         Ok(channels)
     }
 
+    /// Create channel from binary protobuf fields (`Receiver`, `ChannelId`).
+    ///
+    /// Channel kind follows `is_private`. When signature check is on, the
+    /// channel must carry a valid raw `signature`.
+    pub fn parse_bytes(
+        &self,
+        id: Vec<u8>,
+        is_private: bool,
+        signature: &[u8],
+    ) -> ChannelParseResult<Channel> {
+        self.parse_bytes_checked(id, is_private, signature, self.check_key)
+    }
+
+    /// Same as `parse_bytes`, but the signature is checked even when signature check is off.
+    ///
+    /// Nothing passes with an empty key, anybody can sign with it.
+    pub fn parse_signed_bytes(
+        &self,
+        id: Vec<u8>,
+        is_private: bool,
+        signature: &[u8],
+    ) -> ChannelParseResult<Channel> {
+        if self.hasher.key.is_empty() {
+            let channel = Channel::try_from(id).map_err(|_| ParseError::EmptyBytes)?;
+            return Err(ParseError::SignatureMismatch(channel.to_string()));
+        }
+
+        self.parse_bytes_checked(id, is_private, signature, true)
+    }
+
+    fn parse_bytes_checked(
+        &self,
+        id: Vec<u8>,
+        is_private: bool,
+        signature: &[u8],
+        check_key: bool,
+    ) -> ChannelParseResult<Channel> {
+        let channel = Channel::try_from(id).map_err(|_| ParseError::EmptyBytes)?;
+
+        if check_key && !self.hasher.verify(channel.to_string(), signature) {
+            return Err(ParseError::SignatureMismatch(channel.to_string()));
+        }
+
+        Ok(match is_private {
+            true => Channel::create_private(channel.number),
+            false => Channel::create_public(channel.number),
+        })
+    }
+
+    /// Check raw `signature` bytes of a binary channel `id`.
+    ///
+    /// Always passes when signature check is off.
+    pub fn verify(&self, id: &[u8], signature: &[u8]) -> bool {
+        if !self.check_key {
+            return true;
+        }
+
+        match Channel::try_from(id.to_vec()) {
+            Ok(channel) => self.hasher.verify(channel.to_string(), signature),
+            Err(_) => false,
+        }
+    }
+
     pub fn get_key(&self) -> String {
         self.hasher.get_key()
     }
@@ -117,18 +185,42 @@ impl Signature {
     }
 
     pub fn get_digest(&self, data: String) -> String {
-        let mut mac = Hmac::<Sha1>::new_from_slice(&self.key.clone().into_bytes())
-            .expect("Can't create slice key!");
-
-        mac.update(&data.into_bytes());
+        self.get_bytes_digest(data.as_bytes())
+    }
 
-        mac.finalize()
-            .into_bytes()
+    /// Hex encoded digest of arbitrary bytes, e.g. a protobuf request body
+    pub fn get_bytes_digest(&self, data: &[u8]) -> String {
+        self.get_raw_digest(data)
             .into_iter()
             .map(|byte| format!("{:02x?}", byte))
             .collect::<String>()
     }
 
+    /// Digest bytes as they are, for binary protocols
+    pub fn get_raw_digest(&self, data: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.key.clone().into_bytes())
+            .expect("Can't create slice key!");
+
+        mac.update(data);
+
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// Compare raw (not hex encoded) digest bytes with the digest of `data`
+    pub fn verify(&self, data: String, signature: &[u8]) -> bool {
+        self.verify_bytes(data.as_bytes(), signature)
+    }
+
+    /// Same as `verify` for arbitrary bytes
+    pub fn verify_bytes(&self, data: &[u8], signature: &[u8]) -> bool {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.key.clone().into_bytes())
+            .expect("Can't create slice key!");
+
+        mac.update(data);
+
+        mac.verify_slice(signature).is_ok()
+    }
+
     pub fn get_key(&self) -> String {
         self.key.clone()
     }
@@ -174,9 +266,9 @@ impl Channel {
     }
 }
 
-impl ToString for Channel {
-    fn to_string(&self) -> String {
-        self.number.clone()
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.number)
     }
 }
 
@@ -204,6 +296,28 @@ impl TryFrom<Vec<u8>> for Channel {
     }
 }
 
+impl TryFrom<&Channel> for Vec<u8> {
+    type Error = &'static str;
+
+    fn try_from(channel: &Channel) -> Result<Self, Self::Error> {
+        let number = channel.number.as_bytes();
+
+        if number.is_empty() || !number.len().is_multiple_of(2) {
+            return Err("Channel number is not a hex string");
+        }
+
+        number
+            .chunks(2)
+            .map(|pair| {
+                std::str::from_utf8(pair)
+                    .ok()
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                    .ok_or("Channel number is not a hex string")
+            })
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -491,4 +605,143 @@ mod tests {
 
         assert!(channel_result.is_err());
     }
+
+    #[test]
+    fn test_success_get_vec_from_channel() {
+        let channel = Channel::create_private("823f0b607cd6abfc721be5549dadf012".to_string());
+
+        assert_eq!(
+            Vec::<u8>::try_from(&channel).unwrap(),
+            vec![130, 63, 11, 96, 124, 214, 171, 252, 114, 27, 229, 84, 157, 173, 240, 18],
+        )
+    }
+
+    #[test]
+    fn test_broken_get_vec_from_channel() {
+        assert!(Vec::<u8>::try_from(&Channel::create_private("".to_string())).is_err());
+        assert!(Vec::<u8>::try_from(&Channel::create_private("abc".to_string())).is_err());
+        assert!(Vec::<u8>::try_from(&Channel::create_private("xy".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_signature_verify_raw_digest() {
+        let sign = Signature::new("u9kqCo7qhKIQ8RML9xUGNmcZLVWmS8OsR2UN9jsZuaCY3aqPKGENRWmA36f9r47FHnqXlKuMvgsl0hnft7qCAN8iXHw94nHS4D6dxA07BX1lUjwuMJ0t73Z9wJY25Mpu".to_string());
+
+        let digest = Vec::<u8>::try_from(&Channel::create_unknown(
+            "26f59cab4eab972ec7dacec39a4355a3d7627717".to_string(),
+        ))
+        .unwrap();
+
+        assert!(sign.verify("f0e5d42369441879d7e176c96cbbff2d".to_string(), &digest));
+        assert!(!sign.verify("3c8264bab589b0de7174e7b0523a40db".to_string(), &digest));
+        assert!(!sign.verify("f0e5d42369441879d7e176c96cbbff2d".to_string(), &[]));
+    }
+
+    #[test]
+    fn test_signature_bytes() {
+        let sign = Signature::new("secret".to_string());
+        let digest = sign.get_bytes_digest(b"body");
+
+        assert_eq!(digest, sign.get_digest("body".to_string()));
+
+        let raw = Vec::<u8>::try_from(&Channel::create_unknown(digest)).unwrap();
+
+        assert!(sign.verify_bytes(b"body", &raw));
+        assert!(!sign.verify_bytes(b"Body", &raw));
+    }
+
+    #[test]
+    fn test_parser_verify_binary_channel() {
+        let parser = Parser::new(true, Signature::new("u9kqCo7qhKIQ8RML9xUGNmcZLVWmS8OsR2UN9jsZuaCY3aqPKGENRWmA36f9r47FHnqXlKuMvgsl0hnft7qCAN8iXHw94nHS4D6dxA07BX1lUjwuMJ0t73Z9wJY25Mpu".to_string()));
+
+        let id = vec![240, 229, 212, 35, 105, 68, 24, 121, 215, 225, 118, 201, 108, 187, 255, 45];
+        let signature = vec![
+            38, 245, 156, 171, 78, 171, 151, 46, 199, 218, 206, 195, 154, 67, 85, 163, 215, 98, 119, 23,
+        ];
+
+        assert!(parser.verify(&id, &signature));
+        assert!(!parser.verify(&id, &[]));
+        assert!(!parser.verify(&[], &signature));
+    }
+
+    #[test]
+    fn test_parser_verify_without_check() {
+        let parser = Parser::new(false, Signature::default());
+
+        assert!(parser.verify(&[1, 2, 3], &[]));
+    }
+
+    #[test]
+    fn test_parse_bytes_keeps_channel_kind() {
+        let parser = Parser::new(false, Signature::default());
+
+        let id = vec![240, 229, 212, 35, 105, 68, 24, 121, 215, 225, 118, 201, 108, 187, 255, 45];
+
+        assert_eq!(
+            parser.parse_bytes(id.clone(), true, &[]).unwrap(),
+            Channel::create_private("f0e5d42369441879d7e176c96cbbff2d".to_string())
+        );
+        assert_eq!(
+            parser.parse_bytes(id, false, &[]).unwrap(),
+            Channel::create_public("f0e5d42369441879d7e176c96cbbff2d".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_bytes_with_check_hash() {
+        let parser = Parser::new(true, Signature::new("u9kqCo7qhKIQ8RML9xUGNmcZLVWmS8OsR2UN9jsZuaCY3aqPKGENRWmA36f9r47FHnqXlKuMvgsl0hnft7qCAN8iXHw94nHS4D6dxA07BX1lUjwuMJ0t73Z9wJY25Mpu".to_string()));
+
+        let id = vec![240, 229, 212, 35, 105, 68, 24, 121, 215, 225, 118, 201, 108, 187, 255, 45];
+        let signature = vec![
+            38, 245, 156, 171, 78, 171, 151, 46, 199, 218, 206, 195, 154, 67, 85, 163, 215, 98, 119, 23,
+        ];
+
+        assert_eq!(
+            parser.parse_bytes(id.clone(), true, &signature).unwrap(),
+            Channel::create_private("f0e5d42369441879d7e176c96cbbff2d".to_string())
+        );
+        assert!(matches!(
+            parser.parse_bytes(id, true, &[]),
+            Err(ParseError::SignatureMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_signed_bytes_without_check() {
+        let parser = Parser::new(false, Signature::new("u9kqCo7qhKIQ8RML9xUGNmcZLVWmS8OsR2UN9jsZuaCY3aqPKGENRWmA36f9r47FHnqXlKuMvgsl0hnft7qCAN8iXHw94nHS4D6dxA07BX1lUjwuMJ0t73Z9wJY25Mpu".to_string()));
+
+        let id = vec![240, 229, 212, 35, 105, 68, 24, 121, 215, 225, 118, 201, 108, 187, 255, 45];
+        let signature = vec![
+            38, 245, 156, 171, 78, 171, 151, 46, 199, 218, 206, 195, 154, 67, 85, 163, 215, 98, 119, 23,
+        ];
+
+        assert_eq!(
+            parser.parse_signed_bytes(id.clone(), false, &signature).unwrap(),
+            Channel::create_public("f0e5d42369441879d7e176c96cbbff2d".to_string())
+        );
+        assert!(matches!(
+            parser.parse_signed_bytes(id.clone(), false, &signature[1..]),
+            Err(ParseError::SignatureMismatch(_))
+        ));
+
+        /* Valid signature on an empty key */
+        let parser = Parser::new(false, Signature::default());
+        let mut mac = Hmac::<Sha1>::new_from_slice(b"").unwrap();
+        mac.update(b"f0e5d42369441879d7e176c96cbbff2d");
+
+        assert!(matches!(
+            parser.parse_signed_bytes(id, false, &mac.finalize().into_bytes()),
+            Err(ParseError::SignatureMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_empty_bytes() {
+        let parser = Parser::new(false, Signature::default());
+
+        assert!(matches!(
+            parser.parse_bytes(vec![], true, &[]),
+            Err(ParseError::EmptyBytes)
+        ));
+    }
 }
@@ -0,0 +1,197 @@
+use std::fs;
+
+pub const USAGE: &str = "\
+Usage: push-cli [--config FILE] [--url URL] [--ca FILE] [--cert FILE --key FILE] COMMAND
+
+Commands:
+  publish CHANNEL... (--body TEXT | --json JSON | --file PATH) [--expiry SECONDS] [--binary]
+      Publish a message. --binary sends a RequestBatch instead of the text body.
+  channel-stats CHANNEL...
+      Show which channels have subscribers.
+  server-stats
+      Show server statistics.
+
+CHANNEL is a private channel id or `private:public` ids, signed with [security] key.
+The config is --config, $CONFIG_FILE or ./push_config.toml; the url defaults to
+http://127.0.0.1:<general.port>, https:// with [tls]. --ca trusts a self-signed
+server certificate, --cert and --key are for [tls] client_ca_path.";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Args {
+    pub config: Option<String>,
+    pub url: Option<String>,
+    pub tls: Tls,
+    pub command: Command,
+}
+
+/// PEM files for `https://` urls
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Tls {
+    pub ca_path: Option<String>,
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Publish {
+        channels: Vec<String>,
+        body: String,
+        expiry: u32,
+        binary: bool,
+    },
+    ChannelStats {
+        channels: Vec<String>,
+    },
+    ServerStats,
+}
+
+impl Args {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut args = args.into_iter();
+
+        let mut config = None;
+        let mut url = None;
+        let mut tls = Tls::default();
+        let mut body = None;
+        let mut expiry = 0;
+        let mut binary = false;
+        let mut positional = Vec::new();
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{arg} needs a value"));
+
+            match arg.as_str() {
+                "--config" => config = Some(value()?),
+                "--url" => url = Some(value()?.trim_end_matches('/').to_string()),
+                "--ca" => tls.ca_path = Some(value()?),
+                "--cert" => tls.cert_path = Some(value()?),
+                "--key" => tls.key_path = Some(value()?),
+                "--body" => set_body(&mut body, value()?)?,
+                "--json" => set_body(&mut body, compact_json(&value()?)?)?,
+                "--file" => {
+                    let path = value()?;
+                    let text = fs::read_to_string(&path).map_err(|error| format!("{path}: {error}"))?;
+                    set_body(&mut body, text)?
+                }
+                "--expiry" => {
+                    expiry = value()?
+                        .parse()
+                        .map_err(|_| "--expiry must be a number of seconds".to_string())?
+                }
+                "--binary" => binary = true,
+                option if option.starts_with("--") => return Err(format!("Unknown option {option}")),
+                _ => positional.push(arg),
+            }
+        }
+
+        if tls.cert_path.is_some() != tls.key_path.is_some() {
+            return Err("--cert and --key go together".to_string());
+        }
+
+        let mut positional = positional.into_iter();
+
+        let command = match positional.next().as_deref() {
+            Some("publish") => Command::Publish {
+                channels: channels(positional)?,
+                body: body.ok_or("publish needs --body, --json or --file")?,
+                expiry,
+                binary,
+            },
+            Some("channel-stats") => Command::ChannelStats {
+                channels: channels(positional)?,
+            },
+            Some("server-stats") => Command::ServerStats,
+            Some(command) => return Err(format!("Unknown command {command}")),
+            None => return Err("Command is missed".to_string()),
+        };
+
+        Ok(Args { config, url, tls, command })
+    }
+}
+
+fn set_body(body: &mut Option<String>, value: String) -> Result<(), String> {
+    match body.replace(value) {
+        Some(_) => Err("Only one of --body, --json and --file is allowed".to_string()),
+        None => Ok(()),
+    }
+}
+
+/// Checked here so a typo doesn't reach the subscribers
+fn compact_json(value: &str) -> Result<String, String> {
+    serde_json::from_str::<serde_json::Value>(value)
+        .map(|json| json.to_string())
+        .map_err(|error| format!("--json is not valid JSON: {error}"))
+}
+
+fn channels(channels: impl Iterator<Item = String>) -> Result<Vec<String>, String> {
+    let channels = channels.collect::<Vec<_>>();
+
+    match channels.is_empty() {
+        true => Err("At least one channel is needed".to_string()),
+        false => Ok(channels),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Args, String> {
+        Args::parse(line.split_whitespace().map(ToString::to_string))
+    }
+
+    #[test]
+    fn test_publish() {
+        assert_eq!(
+            parse("--url http://localhost:9099/ publish aaa bbb:ccc --json {\"a\":1.0} --expiry 60 --binary"),
+            Ok(Args {
+                config: None,
+                url: Some("http://localhost:9099".to_string()),
+                tls: Tls::default(),
+                command: Command::Publish {
+                    channels: vec!["aaa".to_string(), "bbb:ccc".to_string()],
+                    body: "{\"a\":1.0}".to_string(),
+                    expiry: 60,
+                    binary: true,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        assert_eq!(parse("--config x.toml server-stats").unwrap().command, Command::ServerStats);
+        assert_eq!(
+            parse("channel-stats aaa").unwrap().command,
+            Command::ChannelStats { channels: vec!["aaa".to_string()] }
+        );
+    }
+
+    #[test]
+    fn test_tls() {
+        assert_eq!(
+            parse("--url https://push.local:9099 --ca ca.pem --cert cli.pem --key cli.key server-stats").unwrap().tls,
+            Tls {
+                ca_path: Some("ca.pem".to_string()),
+                cert_path: Some("cli.pem".to_string()),
+                key_path: Some("cli.key".to_string()),
+            }
+        );
+        assert!(parse("--cert cli.pem server-stats").is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(parse("publish aaa").is_err());
+        assert!(parse("publish --body hi").is_err());
+        assert!(parse("publish aaa --body hi --json {}").is_err());
+        assert!(parse("publish aaa --json {").is_err());
+        assert!(parse("publish aaa --body hi --expiry soon").is_err());
+        assert!(parse("channel-stats").is_err());
+        assert!(parse("server-stats --verbose").is_err());
+        assert!(parse("--url").is_err());
+        assert!(parse("subscribe").is_err());
+        assert!(parse("").is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::{crypto::ring, ClientConfig, RootCertStore};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+use crate::args::Tls;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Requests to push-server over `http://` or `https://`
+pub struct Client {
+    /// `scheme://host:port`, paths are appended as the server sees them
+    base: String,
+    agent: ureq::Agent,
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    fn read(response: ureq::Response) -> Result<Self, String> {
+        let status = response.status();
+        let headers = response
+            .headers_names()
+            .into_iter()
+            .filter_map(|name| {
+                let value = response.header(&name)?.to_string();
+                Some((name, value))
+            })
+            .collect();
+
+        let mut body = Vec::new();
+        response
+            .into_reader()
+            .read_to_end(&mut body)
+            .map_err(|error| format!("Couldn't read the response: {error}"))?;
+
+        Ok(Response { status, headers, body })
+    }
+}
+
+impl Client {
+    /// `http://host:port` or `https://host:port`, without a path: the signature covers the path as sent
+    pub fn new(url: &str, tls: &Tls) -> Result<Self, String> {
+        let authority = url
+            .strip_prefix("http://")
+            .or_else(|| url.strip_prefix("https://"))
+            .ok_or_else(|| format!("{url}: only http:// and https:// urls are supported"))?;
+
+        if authority.is_empty() || authority.contains('/') {
+            return Err(format!("{url}: expected http://host:port or https://host:port"));
+        }
+
+        let agent = ureq::AgentBuilder::new()
+            .timeout(TIMEOUT)
+            .tls_config(Arc::new(client_config(tls)?))
+            .build();
+
+        Ok(Client {
+            base: url.to_string(),
+            agent,
+        })
+    }
+
+    pub fn request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> Result<Response, String> {
+        let request = headers
+            .iter()
+            .fold(self.agent.request(method, &format!("{}{path}", self.base)), |request, (name, value)| {
+                request.set(name, value)
+            });
+
+        match request.send_bytes(body) {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => Response::read(response),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+}
+
+/// Public roots plus `--ca`, and the `--cert` client certificate for `[tls] client_ca_path`
+fn client_config(tls: &Tls) -> Result<ClientConfig, String> {
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+
+    if let Some(ca_path) = &tls.ca_path {
+        for certificate in load_certificates(ca_path)? {
+            roots.add(certificate).map_err(|error| format!("{ca_path}: {error}"))?;
+        }
+    }
+
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|error| error.to_string())?
+        .with_root_certificates(roots);
+
+    match (&tls.cert_path, &tls.key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let key = PrivateKeyDer::from_pem_file(key_path).map_err(|error| format!("{key_path}: {error}"))?;
+
+            builder
+                .with_client_auth_cert(load_certificates(cert_path)?, key)
+                .map_err(|error| format!("{cert_path}: {error}"))
+        }
+        _ => Ok(builder.with_no_client_auth()),
+    }
+}
+
+fn load_certificates(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|error| format!("{path}: {error}"))?;
+
+    if certificates.is_empty() {
+        return Err(format!("{path}: no certificates found"));
+    }
+
+    Ok(certificates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/../bitrix-server/testdata/{name}", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    #[test]
+    fn test_client_url() {
+        let tls = Tls::default();
+
+        assert_eq!(Client::new("http://127.0.0.1:9099", &tls).unwrap().base, "http://127.0.0.1:9099");
+        assert_eq!(Client::new("https://127.0.0.1:9099", &tls).unwrap().base, "https://127.0.0.1:9099");
+        assert!(Client::new("ftp://127.0.0.1:9099", &tls).is_err());
+        assert!(Client::new("http://127.0.0.1:9099/bitrix", &tls).is_err());
+    }
+
+    #[test]
+    fn test_client_certificates() {
+        let tls = Tls {
+            ca_path: Some(testdata("first.cert.pem")),
+            cert_path: Some(testdata("second.cert.pem")),
+            key_path: Some(testdata("second.key.pem")),
+        };
+
+        assert!(client_config(&tls).is_ok());
+
+        let mismatched = Tls {
+            key_path: Some(testdata("first.key.pem")),
+            ..tls
+        };
+
+        assert!(client_config(&mismatched).is_err());
+        assert!(client_config(&Tls {
+            ca_path: Some(testdata("missing.pem")),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,63 @@
+use std::env;
+use std::process::ExitCode;
+
+use bitrix_channels::Signature;
+use bitrix_server::settings::Settings;
+
+mod args;
+mod http;
+mod push;
+
+use args::{Args, Command, USAGE};
+
+fn main() -> ExitCode {
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("{error}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(args) {
+        Ok(output) => {
+            if !output.is_empty() {
+                println!("{output}");
+            }
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("{error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: Args) -> Result<String, String> {
+    let settings = match &args.config {
+        Some(config_file) => Settings::from_file(config_file),
+        None => Settings::new(),
+    }
+    .map_err(|error| format!("Couldn't read config: {error}"))?;
+
+    let url = match args.url {
+        Some(url) => url,
+        None if settings.tls.is_some() => format!("https://127.0.0.1:{}", settings.general.port),
+        None => format!("http://127.0.0.1:{}", settings.general.port),
+    };
+
+    let push = push::Push::new(http::Client::new(&url, &args.tls)?, Signature::new(settings.security.key));
+
+    match args.command {
+        Command::Publish { channels, body, expiry, binary: false } => {
+            push::success(push.publish_text(&channels, &body, expiry)?)?;
+            Ok(String::new())
+        }
+        Command::Publish { channels, body, expiry, binary: true } => {
+            push::success(push.publish_binary(&channels, &body, expiry)?)?;
+            Ok(String::new())
+        }
+        Command::ChannelStats { channels } => push.channel_stats(&channels),
+        Command::ServerStats => push.server_stats(),
+    }
+}
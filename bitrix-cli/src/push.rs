@@ -0,0 +1,256 @@
+use bitrix_channels::Signature;
+use bitrix_server::{
+    access::{self, SIGNATURE_HEADER, TIMESTAMP_HEADER},
+    items, utils,
+};
+use prost::Message;
+
+use crate::http::{Client, Response};
+
+/// Requests to push-server as the Bitrix backend makes them
+pub struct Push {
+    client: Client,
+    signature: Signature,
+}
+
+impl Push {
+    pub fn new(client: Client, signature: Signature) -> Self {
+        Push { client, signature }
+    }
+
+    /// `CHANNEL_ID` value, every id signed like the Bitrix backend does
+    pub fn channel_ids(&self, channels: &[String]) -> String {
+        channels
+            .iter()
+            .map(|channel| format!("{channel}.{}", self.signature.get_digest(channel.clone())))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Binary receivers: the private id and, after `:`, the public one
+    pub fn receivers(&self, channels: &[String]) -> Result<Vec<items::Receiver>, String> {
+        let mut receivers = Vec::new();
+
+        for channel in channels {
+            let (private, public) = match channel.split_once(':') {
+                Some((private, public)) => (private, Some(public)),
+                None => (channel.as_str(), None),
+            };
+
+            receivers.push(self.receiver(private, true)?);
+
+            if let Some(public) = public {
+                receivers.push(self.receiver(public, false)?);
+            }
+        }
+
+        Ok(receivers)
+    }
+
+    fn receiver(&self, channel: &str, is_private: bool) -> Result<items::Receiver, String> {
+        let invalid = || format!("{channel}: channel id must be hex");
+
+        Ok(items::Receiver {
+            id: utils::decode_hex(channel).filter(|id| !id.is_empty()).ok_or_else(invalid)?,
+            is_private,
+            signature: utils::decode_hex(&self.signature.get_digest(channel.to_string()))
+                .ok_or_else(invalid)?,
+        })
+    }
+
+    /// `POST /bitrix/pub/?CHANNEL_ID=...` with the body as the message text
+    pub fn publish_text(&self, channels: &[String], body: &str, expiry: u32) -> Result<Response, String> {
+        let path = format!("/bitrix/pub/?CHANNEL_ID={}", self.channel_ids(channels));
+
+        self.send("POST", &path, vec![("Message-Expiry", expiry.to_string())], body.as_bytes())
+    }
+
+    /// `POST /bitrix/pub/?binaryMode=true` with one `IncomingMessage`
+    pub fn publish_binary(&self, channels: &[String], body: &str, expiry: u32) -> Result<Response, String> {
+        let batch = items::RequestBatch {
+            requests: vec![items::Request {
+                command: Some(items::request::Command::IncomingMessages(
+                    items::IncomingMessagesRequest {
+                        messages: vec![items::IncomingMessage {
+                            receivers: self.receivers(channels)?,
+                            sender: Some(items::Sender {
+                                r#type: items::SenderType::Backend as i32,
+                                id: vec![],
+                            }),
+                            body: body.to_string(),
+                            expiry,
+                            r#type: String::new(),
+                        }],
+                    },
+                )),
+            }],
+        };
+
+        self.send(
+            "POST",
+            "/bitrix/pub/?binaryMode=true",
+            vec![("Content-Type", "application/x-protobuf".to_string())],
+            &batch.encode_to_vec(),
+        )
+    }
+
+    /// `GET /bitrix/pub/?CHANNEL_ID=...`, one `id private|public online|offline` line per channel
+    pub fn channel_stats(&self, channels: &[String]) -> Result<String, String> {
+        let path = format!("/bitrix/pub/?CHANNEL_ID={}", self.channel_ids(channels));
+        let response = success(self.send("GET", &path, vec![], &[])?)?;
+
+        let batch = items::ResponseBatch::decode(response.body.as_slice())
+            .map_err(|error| format!("Couldn't decode channel stats: {error}"))?;
+
+        let lines = batch
+            .responses
+            .into_iter()
+            .filter_map(|response| match response.command {
+                Some(items::response::Command::ChannelStats(stats)) => Some(stats.channels),
+                _ => None,
+            })
+            .flatten()
+            .map(|channel| {
+                format!(
+                    "{} {} {}",
+                    utils::encode_hex(&channel.id),
+                    if channel.is_private { "private" } else { "public" },
+                    if channel.is_online { "online" } else { "offline" },
+                )
+            })
+            .collect::<Vec<_>>();
+
+        Ok(lines.join("\n"))
+    }
+
+    /// `GET /bitrix/server-stat/`, pretty printed
+    pub fn server_stats(&self) -> Result<String, String> {
+        let response = success(self.send("GET", "/bitrix/server-stat/", vec![], &[])?)?;
+
+        let stats = serde_json::from_slice::<serde_json::Value>(&response.body)
+            .map_err(|error| format!("Couldn't decode server stats: {error}"))?;
+
+        serde_json::to_string_pretty(&stats).map_err(|error| error.to_string())
+    }
+
+    /// `X-PUSH-SIGNATURE` over `access::signed_content` and the timestamp it was made at
+    fn signature_headers(&self, timestamp: u32, method: &str, path: &str, body: &[u8]) -> [(&'static str, String); 2] {
+        let content = access::signed_content(timestamp, method, path, body);
+
+        [
+            (SIGNATURE_HEADER, self.signature.get_bytes_digest(&content)),
+            (TIMESTAMP_HEADER, timestamp.to_string()),
+        ]
+    }
+
+    /// Signs the request for `[access] require_signature`, the server ignores it otherwise
+    fn send(
+        &self,
+        method: &str,
+        path: &str,
+        mut headers: Vec<(&str, String)>,
+        body: &[u8],
+    ) -> Result<Response, String> {
+        headers.extend(self.signature_headers(utils::get_timestamp(), method, path, body));
+
+        self.client
+            .request(method, path, &headers, body)
+            .map_err(|error| format!("Request to push-server failed: {error}"))
+    }
+}
+
+/// Non 2xx responses as an error with `X-PUSH-ERR` when there is one
+pub fn success(response: Response) -> Result<Response, String> {
+    if response.is_success() {
+        return Ok(response);
+    }
+
+    let mut error = format!("push-server answered {}", response.status);
+
+    if let Some(push_error) = response.header("X-PUSH-ERR") {
+        error.push_str(&format!(": {push_error}"));
+    }
+    if !response.body.is_empty() {
+        error.push_str(&format!("\n{}", String::from_utf8_lossy(&response.body)));
+    }
+
+    Err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNEL: &str = "f0e5d42369441879d7e176c96cbbff2d";
+    const PUBLIC: &str = "3c8264bab589b0de7174e7b0523a40db";
+
+    fn push() -> Push {
+        Push::new(
+            Client::new("http://127.0.0.1:9099", &Default::default()).unwrap(),
+            Signature::new("secret".to_string()),
+        )
+    }
+
+    #[test]
+    fn test_channel_ids_pass_the_parser() {
+        let signature = Signature::new("secret".to_string());
+        let parser = bitrix_channels::Parser::new(true, signature);
+
+        let channel_ids = push().channel_ids(&[format!("{CHANNEL}:{PUBLIC}")]);
+        let channels = parser.parse(channel_ids).unwrap();
+
+        assert_eq!(channels.len(), 2);
+        assert_eq!(channels[0].to_string(), CHANNEL);
+        assert_eq!(channels[1].to_string(), PUBLIC);
+    }
+
+    #[test]
+    fn test_receivers_pass_the_parser() {
+        let parser = bitrix_channels::Parser::new(true, Signature::new("secret".to_string()));
+
+        let receivers = push().receivers(&[format!("{CHANNEL}:{PUBLIC}")]).unwrap();
+
+        assert_eq!(receivers.len(), 2);
+        assert!(receivers[0].is_private);
+        assert!(!receivers[1].is_private);
+
+        for receiver in receivers {
+            assert!(parser
+                .parse_bytes(receiver.id, receiver.is_private, &receiver.signature)
+                .is_ok());
+        }
+
+        assert!(push().receivers(&["not hex".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_signature_headers_match_the_server() {
+        let path = format!("/bitrix/pub/?CHANNEL_ID={}", push().channel_ids(&[CHANNEL.to_string()]));
+        let [(signature_header, digest), (timestamp_header, timestamp)] =
+            push().signature_headers(1_700_000_000, "GET", &path, b"");
+
+        assert_eq!(signature_header, SIGNATURE_HEADER);
+        assert_eq!(timestamp_header, TIMESTAMP_HEADER);
+        assert_eq!(timestamp, "1700000000");
+
+        let key = Signature::new("secret".to_string());
+        let digest = utils::decode_hex(&digest).unwrap();
+
+        assert!(key.verify_bytes(&access::signed_content(1_700_000_000, "GET", &path, b""), &digest));
+        assert!(!key.verify_bytes(&access::signed_content(1_700_000_000, "POST", &path, b""), &digest));
+    }
+
+    #[test]
+    fn test_success() {
+        let response = Response {
+            status: 403,
+            headers: vec![("x-push-err".to_string(), "[EAC003] Signature mismatch".to_string())],
+            body: vec![],
+        };
+
+        assert_eq!(
+            success(response).unwrap_err(),
+            "push-server answered 403: [EAC003] Signature mismatch"
+        );
+    }
+}
@@ -0,0 +1,154 @@
+//! Publish throughput of `ShardRouter` by the number of shards.
+//!
+//! Publishers run on their own arbiters like HTTP workers do and hand every
+//! publish straight to the shard of its channel.
+//!
+//! `cargo bench -p bitrix_server --bench fan_out`
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use bitrix_channels::Channel;
+use bitrix_server::{
+    backend::InProcessBackend,
+    items,
+    message::{ChannelMessage, Client, DisconnectMessage, ProtobufMessage},
+    router::ShardRouter,
+    server::WsPullServer,
+    settings::{self, Backpressure},
+};
+
+const CHANNELS: usize = 64;
+const SUBSCRIBERS_PER_CHANNEL: usize = 200;
+const MESSAGES_PER_CHANNEL: usize = 50;
+const SUBSCRIBER_ARBITERS: usize = 4;
+const PUBLISHER_ARBITERS: usize = 4;
+
+struct Subscriber(Arc<AtomicU64>);
+
+impl Actor for Subscriber {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.set_mailbox_capacity(MESSAGES_PER_CHANNEL * 2);
+    }
+}
+
+impl Handler<ChannelMessage> for Subscriber {
+    type Result = ();
+
+    fn handle(&mut self, _msg: ChannelMessage, _ctx: &mut Self::Context) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Handler<DisconnectMessage> for Subscriber {
+    type Result = ();
+
+    fn handle(&mut self, _msg: DisconnectMessage, ctx: &mut Self::Context) {
+        ctx.stop();
+    }
+}
+
+fn message() -> ProtobufMessage {
+    ProtobufMessage::new(items::ResponseBatch {
+        responses: vec![items::Response {
+            command: Some(items::response::Command::OutgoingMessages(
+                items::OutgoingMessagesResponse {
+                    messages: vec![items::OutgoingMessage {
+                        id: vec![0; 16],
+                        body: r#"{"module_id":"im","command":"message","params":{}}"#.to_string(),
+                        ..Default::default()
+                    }],
+                },
+            )),
+        }],
+    })
+}
+
+async fn run(shards: usize, arbiters: &[ArbiterHandle], publishers: &[ArbiterHandle]) -> Duration {
+    let history = settings::History { size: 0, ..Default::default() };
+    let router = ShardRouter::new(
+        WsPullServer::default().start(),
+        &history,
+        Box::new(InProcessBackend),
+        shards,
+        Backpressure::default(),
+    );
+    let delivered = Arc::new(AtomicU64::new(0));
+
+    let channels = (0..CHANNELS)
+        .map(|number| Channel::create_private(format!("{number:032x}")))
+        .collect::<Vec<_>>();
+
+    for (number, channel) in channels.iter().enumerate() {
+        for subscriber_number in 0..SUBSCRIBERS_PER_CHANNEL {
+            let arbiter = &arbiters[(number + subscriber_number) % arbiters.len()];
+            let counter = delivered.clone();
+            let subscriber = Subscriber::start_in_arbiter(arbiter, move |_| Subscriber(counter));
+
+            router.subscribe(vec![channel.clone()], Client::new(subscriber), None);
+        }
+    }
+
+    /* Let the shards take every subscription before the clock starts */
+    actix::clock::sleep(Duration::from_millis(200)).await;
+
+    let expected = (CHANNELS * SUBSCRIBERS_PER_CHANNEL * MESSAGES_PER_CHANNEL) as u64;
+    let started = Instant::now();
+
+    for (number, publisher) in publishers.iter().enumerate() {
+        let router = router.clone();
+        let channels = channels.iter().skip(number).step_by(publishers.len()).cloned().collect::<Vec<_>>();
+
+        publisher.spawn(async move {
+            for _ in 0..MESSAGES_PER_CHANNEL {
+                for channel in channels.iter() {
+                    router.publish(vec![channel.clone()], message());
+                }
+            }
+        });
+    }
+
+    while delivered.load(Ordering::Relaxed) < expected {
+        if started.elapsed() > Duration::from_secs(60) {
+            panic!("Delivered only {} of {expected}", delivered.load(Ordering::Relaxed));
+        }
+
+        actix::clock::sleep(Duration::from_millis(1)).await;
+    }
+
+    started.elapsed()
+}
+
+fn main() {
+    let system = System::new();
+
+    system.block_on(async {
+        let arbiters = (0..SUBSCRIBER_ARBITERS)
+            .map(|_| Arbiter::new().handle())
+            .collect::<Vec<_>>();
+        let publishers = (0..PUBLISHER_ARBITERS)
+            .map(|_| Arbiter::new().handle())
+            .collect::<Vec<_>>();
+
+        let deliveries = CHANNELS * SUBSCRIBERS_PER_CHANNEL * MESSAGES_PER_CHANNEL;
+
+        println!(
+            "{CHANNELS} channels x {SUBSCRIBERS_PER_CHANNEL} subscribers x {MESSAGES_PER_CHANNEL} messages, \
+             {PUBLISHER_ARBITERS} publishers"
+        );
+
+        for shards in [1, 2, 4, 8] {
+            let elapsed = run(shards, &arbiters, &publishers).await;
+
+            println!(
+                "shards {shards:>2}: {:>8.1} ms, {:>10.0} deliveries/s",
+                elapsed.as_secs_f64() * 1000.0,
+                deliveries as f64 / elapsed.as_secs_f64()
+            );
+        }
+    });
+}
@@ -0,0 +1,508 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::ContentType;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use bitrix_channels::Signature;
+
+use crate::{reload::Swap, settings, utils};
+
+/// Hex encoded HMAC of `signed_content` made with `[security] key`
+pub const SIGNATURE_HEADER: &str = "X-PUSH-SIGNATURE";
+/// Unix time the request was signed at
+pub const TIMESTAMP_HEADER: &str = "X-PUSH-TIMESTAMP";
+/// Seconds a signature stays valid either way, covers clock drift between the backend and us
+pub const SIGNATURE_MAX_AGE: u32 = 300;
+
+const FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+
+/// Network in CIDR notation, a plain address is a network of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                mask(u32::from(network).into(), u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                mask(network.into(), ip.into(), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Compare the first `prefix` bits of `width` bit addresses
+fn mask(network: u128, ip: u128, width: u8, prefix: u8) -> bool {
+    let shift = width - prefix;
+
+    prefix == 0 || network >> shift == ip >> shift
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+
+        let network = IpAddr::from_str(address.trim())
+            .map_err(|error| format!("{value}: {error}"))?;
+
+        let width = if network.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= width)
+                .ok_or_else(|| format!("{value}: prefix must be 0..={width}"))?,
+            None => width,
+        };
+
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Who may call trusted endpoints, built once from `[access]`
+#[derive(Debug, Clone, Default)]
+pub struct AccessPolicy {
+    allow: Vec<Cidr>,
+    proxies: Vec<Cidr>,
+    signature: Option<Signature>,
+}
+
+impl AccessPolicy {
+    pub fn new(access: &settings::Access, security: &settings::Security) -> Result<Self, String> {
+        Ok(AccessPolicy {
+            allow: parse_networks(&access.allow)?,
+            proxies: parse_networks(&access.trusted_proxies)?,
+            signature: access
+                .require_signature
+                .then(|| Signature::new(security.key.clone())),
+        })
+    }
+
+    /// Any address when the allowlist is empty
+    pub fn allows(&self, ip: Option<IpAddr>) -> bool {
+        if self.allow.is_empty() {
+            return true;
+        }
+
+        ip.is_some_and(|ip| self.allow.iter().any(|cidr| cidr.contains(ip)))
+    }
+
+    /// Whether trusted endpoints are closed to anybody at all
+    pub fn is_restricted(&self) -> bool {
+        !self.allow.is_empty() || self.signature.is_some()
+    }
+
+    /// Whether trusted endpoints are closed by address, the only way for a browser page
+    pub fn has_allowlist(&self) -> bool {
+        !self.allow.is_empty()
+    }
+
+    /// Address of the client behind trusted proxies.
+    ///
+    /// `X-Forwarded-For` is read from the right, every proxy appends the address
+    /// it got the request from. The first one that is not a trusted proxy is the
+    /// client, what comes before it may be forged. `None` when that entry is not
+    /// an address.
+    pub fn client_ip(&self, peer: Option<IpAddr>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        let is_proxy = |ip: IpAddr| self.proxies.iter().any(|cidr| cidr.contains(ip));
+
+        let (Some(mut client), Some(forwarded_for)) = (peer, forwarded_for) else {
+            return peer;
+        };
+
+        for hop in forwarded_for.rsplit(',') {
+            if !is_proxy(client) {
+                break;
+            }
+
+            client = IpAddr::from_str(hop.trim()).ok()?;
+        }
+
+        Some(client)
+    }
+}
+
+fn parse_networks(networks: &[String]) -> Result<Vec<Cidr>, String> {
+    networks.iter().map(|cidr| cidr.parse()).collect()
+}
+
+/// What `X-PUSH-SIGNATURE` is the hex HMAC of.
+///
+/// The `X-PUSH-TIMESTAMP` value, the method and the path with the query as the
+/// server gets it, one per line, then the body. A bodyless `GET` differs by its
+/// query and timestamp, so a captured signature is useless after `SIGNATURE_MAX_AGE`.
+pub fn signed_content(timestamp: u32, method: &str, path_and_query: &str, body: &[u8]) -> Vec<u8> {
+    [format!("{timestamp}\n{method}\n{path_and_query}\n").as_bytes(), body].concat()
+}
+
+/// Middleware for trusted endpoints: `/pub/`, `/server-stat/`, admin API.
+///
+/// Refuses everything unless `Swap<AccessPolicy>` is registered as app data.
+/// A signed body is buffered up to the `PayloadConfig` limit, bigger ones get `413`.
+pub async fn trusted(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let Some(policy) = req.app_data::<web::Data<Swap<AccessPolicy>>>().map(|policy| policy.load()) else {
+        log::error!("Trusted request without an access policy, refused");
+        return Ok(reject(req, "[EAC007] Access policy is not configured".to_string()));
+    };
+
+    if !allows_client(&req, &policy) {
+        return Ok(reject(req, "[EAC001] Address is not allowed".to_string()));
+    }
+
+    if let Some(signature) = &policy.signature {
+        let digest = header(&req, SIGNATURE_HEADER).and_then(utils::decode_hex);
+
+        let Some(digest) = digest else {
+            return Ok(reject(req, "[EAC002] Signature is missed".to_string()));
+        };
+
+        let timestamp = header(&req, TIMESTAMP_HEADER)
+            .and_then(|timestamp| timestamp.parse::<u32>().ok())
+            .filter(|timestamp| timestamp.abs_diff(utils::get_timestamp()) <= SIGNATURE_MAX_AGE);
+
+        let Some(timestamp) = timestamp else {
+            return Ok(reject(req, "[EAC004] Signature timestamp is missed or expired".to_string()));
+        };
+
+        let body = match req.extract::<web::Bytes>().await {
+            Ok(body) => body,
+            Err(error) => return Ok(req.error_response(error).map_into_right_body()),
+        };
+
+        let path_and_query = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        let content = signed_content(timestamp, req.method().as_str(), path_and_query, &body);
+
+        if !signature.verify_bytes(&content, &digest) {
+            return Ok(reject(req, "[EAC003] Signature mismatch".to_string()));
+        }
+
+        /* Handlers read the body again */
+        req.set_payload(Payload::from(body));
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+/// Middleware for the `[metrics]` listener, only the allowlist applies.
+///
+/// A scraper can't sign its requests, so `require_signature` is not checked.
+pub async fn allowed_address(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let Some(policy) = req.app_data::<web::Data<Swap<AccessPolicy>>>().map(|policy| policy.load()) else {
+        log::error!("Trusted request without an access policy, refused");
+        return Ok(reject(req, "[EAC007] Access policy is not configured".to_string()));
+    };
+
+    if !allows_client(&req, &policy) {
+        return Ok(reject(req, "[EAC001] Address is not allowed".to_string()));
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+fn allows_client(req: &ServiceRequest, policy: &AccessPolicy) -> bool {
+    let client_ip = policy.client_ip(req.peer_addr().map(|addr| addr.ip()), header(req, FORWARDED_FOR_HEADER));
+
+    if !policy.allows(client_ip) {
+        log::warn!("Trusted request from not allowed address {client_ip:?}, peer {:?}", req.peer_addr());
+        return false;
+    }
+
+    true
+}
+
+/// Middleware for endpoints too dangerous to leave open: the admin API.
+///
+/// Refuses every request unless `[access]` has an allowlist or `require_signature`.
+/// Checked per request, since a reload may relax `[access]`. Goes outside `trusted`.
+pub async fn restricted(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    require(req, next, AccessPolicy::is_restricted, "[EAC005] Needs [access] allow or require_signature").await
+}
+
+/// Middleware for pages a browser opens: the inspector.
+///
+/// Like `restricted`, but only an allowlist will do, a browser can't sign requests.
+pub async fn allowlisted(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    require(req, next, AccessPolicy::has_allowlist, "[EAC006] Needs [access] allow").await
+}
+
+async fn require<B: MessageBody + 'static>(
+    req: ServiceRequest,
+    next: Next<B>,
+    check: fn(&AccessPolicy) -> bool,
+    error: &str,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    let allowed = req
+        .app_data::<web::Data<Swap<AccessPolicy>>>()
+        .is_some_and(|policy| check(&policy.load()));
+
+    if !allowed {
+        return Ok(reject(req, error.to_string()));
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+fn header<'a>(req: &'a ServiceRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|value| value.to_str().ok())
+}
+
+fn reject<B>(req: ServiceRequest, error: String) -> ServiceResponse<EitherBody<B>> {
+    req.into_response(
+        HttpResponse::Forbidden()
+            .insert_header(("X-PUSH-ERR", error))
+            .content_type(ContentType::plaintext())
+            .finish(),
+    )
+    .map_into_right_body()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{middleware::from_fn, App};
+
+    use super::*;
+
+    fn cidr(value: &str) -> Cidr {
+        value.parse().unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn policy(allow: &[&str], require_signature: bool) -> AccessPolicy {
+        AccessPolicy::new(
+            &settings::Access {
+                allow: allow.iter().map(|cidr| cidr.to_string()).collect(),
+                require_signature,
+                trusted_proxies: vec!["127.0.0.1".to_string()],
+            },
+            &settings::Security {
+                enabled: false,
+                key: "secret".to_string(),
+            },
+        )
+        .unwrap()
+    }
+
+    async fn call(policy: AccessPolicy, req: TestRequest) -> (u16, Option<String>, String) {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Swap::new(policy)))
+                .app_data(web::PayloadConfig::new(64))
+                .service(
+                    web::resource("/pub/")
+                        .wrap(from_fn(trusted))
+                        .to(|body: web::Bytes| async move { HttpResponse::Ok().body(body) }),
+                ),
+        )
+        .await;
+
+        let response = call_service(&app, req.uri("/pub/").to_request()).await;
+        let status = response.status().as_u16();
+        let error = response
+            .headers()
+            .get("X-PUSH-ERR")
+            .map(|value| value.to_str().unwrap().to_string());
+        let body = read_body(response).await;
+
+        (status, error, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn test_cidr() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.20.30.40")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr("192.168.1.7").contains(ip("192.168.1.7")));
+        assert!(!cidr("192.168.1.7").contains(ip("192.168.1.8")));
+        assert!(cidr("0.0.0.0/0").contains(ip("8.8.8.8")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:1::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("10.0.0.1")));
+        assert!(cidr("127.0.0.0/8").contains(ip("::ffff:127.0.0.1")));
+
+        assert_eq!(cidr("::1").to_string(), "::1/128");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_allowlist() {
+        let allowed = TestRequest::get().peer_addr("10.1.2.3:5000".parse().unwrap());
+        assert_eq!(call(policy(&["10.0.0.0/8"], false), allowed).await.0, 200);
+
+        let denied = TestRequest::get().peer_addr("192.168.0.1:5000".parse().unwrap());
+        assert_eq!(
+            call(policy(&["10.0.0.0/8"], false), denied).await,
+            (403, Some("[EAC001] Address is not allowed".to_string()), String::new())
+        );
+
+        let anyone = TestRequest::get().peer_addr("192.168.0.1:5000".parse().unwrap());
+        assert_eq!(call(policy(&[], false), anyone).await.0, 200);
+    }
+
+    #[actix_web::test]
+    async fn test_allowed_address_needs_no_signature() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Swap::new(policy(&["10.0.0.0/8"], true))))
+                .service(web::resource("/metrics").wrap(from_fn(allowed_address)).to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for (peer, status) in [("10.1.2.3:5000", 200), ("192.168.0.1:5000", 403)] {
+            let request = TestRequest::get().uri("/metrics").peer_addr(peer.parse().unwrap());
+
+            assert_eq!(call_service(&app, request.to_request()).await.status(), status);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_no_policy_is_refused() {
+        let app = init_service(
+            App::new().service(web::resource("/pub/").wrap(from_fn(trusted)).to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let response = call_service(&app, TestRequest::get().uri("/pub/").to_request()).await;
+
+        assert_eq!(response.status(), 403);
+        assert_eq!(response.headers().get("X-PUSH-ERR").unwrap(), "[EAC007] Access policy is not configured");
+    }
+
+    #[actix_web::test]
+    async fn test_client_behind_proxy() {
+        let behind_proxy = TestRequest::get()
+            .peer_addr("127.0.0.1:5000".parse().unwrap())
+            .insert_header((FORWARDED_FOR_HEADER, "192.168.0.1, 10.1.2.3"));
+        assert_eq!(call(policy(&["10.0.0.0/8"], false), behind_proxy).await.0, 200);
+
+        /* Only a trusted proxy may say who the client is */
+        let forged = TestRequest::get()
+            .peer_addr("192.168.0.1:5000".parse().unwrap())
+            .insert_header((FORWARDED_FOR_HEADER, "10.1.2.3"));
+        assert_eq!(call(policy(&["10.0.0.0/8"], false), forged).await.0, 403);
+
+        let policy = policy(&[], false);
+        let proxy = Some(ip("127.0.0.1"));
+
+        assert_eq!(policy.client_ip(proxy, None), proxy);
+        assert_eq!(policy.client_ip(proxy, Some("10.1.2.3")), Some(ip("10.1.2.3")));
+        assert_eq!(policy.client_ip(proxy, Some("10.1.2.3, 127.0.0.1")), Some(ip("10.1.2.3")));
+        assert_eq!(policy.client_ip(proxy, Some("127.0.0.1")), proxy);
+        assert_eq!(policy.client_ip(proxy, Some("unknown")), None);
+        assert_eq!(policy.client_ip(Some(ip("10.0.0.1")), Some("192.168.0.1")), Some(ip("10.0.0.1")));
+    }
+
+    /// Request signed at `timestamp` for `method` and `path_and_query`, with `body` as its payload
+    fn signed(req: TestRequest, timestamp: u32, method: &str, path_and_query: &str, body: &str) -> TestRequest {
+        let content = signed_content(timestamp, method, path_and_query, body.as_bytes());
+        let digest = Signature::new("secret".to_string()).get_bytes_digest(&content);
+
+        req.insert_header((SIGNATURE_HEADER, digest))
+            .insert_header((TIMESTAMP_HEADER, timestamp.to_string()))
+            .set_payload(body.to_string())
+    }
+
+    async fn error(req: TestRequest) -> Option<String> {
+        call(policy(&[], true), req).await.1
+    }
+
+    #[actix_web::test]
+    async fn test_signature() {
+        let now = utils::get_timestamp();
+
+        let post = signed(TestRequest::post(), now, "POST", "/pub/", "hello");
+        assert_eq!(call(policy(&[], true), post).await, (200, None, "hello".to_string()));
+
+        let get = signed(TestRequest::get(), now - 10, "GET", "/pub/", "");
+        assert_eq!(call(policy(&[], true), get).await.0, 200);
+
+        let missed = TestRequest::post().set_payload("hello");
+        assert_eq!(error(missed).await.as_deref(), Some("[EAC002] Signature is missed"));
+
+        let forged = signed(TestRequest::post(), now, "POST", "/pub/", "hello").set_payload("hello!");
+        assert_eq!(error(forged).await.as_deref(), Some("[EAC003] Signature mismatch"));
+    }
+
+    #[actix_web::test]
+    async fn test_signature_is_not_replayable() {
+        let now = utils::get_timestamp();
+        let expired = "[EAC004] Signature timestamp is missed or expired";
+
+        let other_method = signed(TestRequest::get(), now, "DELETE", "/pub/", "");
+        assert_eq!(error(other_method).await.as_deref(), Some("[EAC003] Signature mismatch"));
+
+        let other_query = signed(TestRequest::get(), now, "GET", "/pub/?CHANNEL_ID=abc", "");
+        assert_eq!(error(other_query).await.as_deref(), Some("[EAC003] Signature mismatch"));
+
+        let other_time = signed(TestRequest::get(), now, "GET", "/pub/", "")
+            .insert_header((TIMESTAMP_HEADER, (now - 1).to_string()));
+        assert_eq!(error(other_time).await.as_deref(), Some("[EAC003] Signature mismatch"));
+
+        let old = signed(TestRequest::get(), now - SIGNATURE_MAX_AGE - 1, "GET", "/pub/", "");
+        assert_eq!(error(old).await.as_deref(), Some(expired));
+
+        let future = signed(TestRequest::get(), now + SIGNATURE_MAX_AGE + 1, "GET", "/pub/", "");
+        assert_eq!(error(future).await.as_deref(), Some(expired));
+
+        let digest = Signature::new("secret".to_string()).get_bytes_digest(b"");
+        let without_timestamp = TestRequest::get().insert_header((SIGNATURE_HEADER, digest));
+        assert_eq!(error(without_timestamp).await.as_deref(), Some(expired));
+    }
+
+    #[actix_web::test]
+    async fn test_signed_body_is_capped() {
+        let body = "x".repeat(65);
+        let large = signed(TestRequest::post(), utils::get_timestamp(), "POST", "/pub/", &body);
+
+        assert_eq!(call(policy(&[], true), large).await.0, 413);
+    }
+
+    #[test]
+    fn test_invalid_allowlist() {
+        let access = settings::Access {
+            allow: vec!["10.0.0.0/8".to_string(), "nonsense".to_string()],
+            require_signature: false,
+            trusted_proxies: vec![],
+        };
+
+        assert!(AccessPolicy::new(&access, &settings::Security { enabled: false, key: String::new() }).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use actix::SystemService;
+use actix_web::http::header::ContentType;
+use actix_web::{web, Error, HttpResponse};
+use actix_web_actors::ws::{CloseCode, CloseReason};
+use bitrix_channels::Channel;
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    message::{KickMessage, SessionFilter, SessionsMessage},
+    server::WsPullServer,
+};
+
+/*
+Admin API under `/bitrix`, the whole scope is a trusted request. It answers only
+while `[access]` has an allowlist or `require_signature`, see `access::restricted`:
+
+GET /admin/sessions/ -> live sessions.
+DELETE /admin/sessions/{id}/?code=4000&reason=... -> close one session.
+GET /admin/channels/{channel}/ -> subscribers of a channel.
+DELETE /admin/channels/{channel}/?code=4000&reason=... -> close every subscriber of a channel.
+*/
+
+pub fn routes_configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/sessions/").route(web::get().to(sessions)))
+        .service(web::resource("/sessions/{id}/").route(web::delete().to(kick_session)))
+        .service(web::resource("/channels/{channel}/")
+            .route(web::get().to(channel_sessions))
+            .route(web::delete().to(kick_channel)));
+}
+
+#[derive(Deserialize, Debug)]
+struct KickQuery {
+    code: Option<u16>,
+    reason: Option<String>,
+}
+
+impl KickQuery {
+    /// Close frame to send, 1000 by default.
+    ///
+    /// Only codes an endpoint may send (RFC 6455, 7.4): 1004 is reserved, 1005,
+    /// 1006 and 1015 never go on the wire, 1016..=2999 are not assigned yet.
+    fn close_reason(&self) -> Result<CloseReason, String> {
+        let code = self.code.unwrap_or(1000);
+
+        if !matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999) {
+            return Err(format!("[EAD001] Close code must be 1000-1003, 1007-1014 or 3000-4999: {code}"));
+        }
+
+        Ok(CloseReason {
+            code: CloseCode::from(code),
+            description: Some(self.reason.clone().unwrap_or_else(|| "Closed by admin".to_string())),
+        })
+    }
+}
+
+fn bad_request(error: String) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .insert_header(("X-PUSH-ERR", error))
+        .content_type(ContentType::plaintext())
+        .finish()
+}
+
+async fn select(filter: SessionFilter) -> Result<HttpResponse, Error> {
+    let sessions = WsPullServer::from_registry()
+        .send(SessionsMessage(filter))
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+async fn kick(filter: SessionFilter, reason: CloseReason) -> Result<usize, Error> {
+    log::info!("Admin closes sessions {filter:?} with {reason:?}");
+
+    WsPullServer::from_registry()
+        .send(KickMessage(filter, reason))
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)
+}
+
+/// `GET /admin/sessions/`
+async fn sessions() -> Result<HttpResponse, Error> {
+    select(SessionFilter::All).await
+}
+
+/// `DELETE /admin/sessions/{id}/`
+async fn kick_session(id: web::Path<String>, query: web::Query<KickQuery>) -> Result<HttpResponse, Error> {
+    let Ok(id) = Uuid::parse_str(&id) else {
+        return Ok(bad_request(format!("[EAD002] Session id is not valid: {id}")));
+    };
+
+    let reason = match query.close_reason() {
+        Ok(reason) => reason,
+        Err(error) => return Ok(bad_request(error)),
+    };
+
+    match kick(SessionFilter::Id(id), reason).await? {
+        0 => Ok(HttpResponse::NotFound().finish()),
+        disconnected => Ok(HttpResponse::Ok().json(json!({ "disconnected": disconnected }))),
+    }
+}
+
+/// `GET /admin/channels/{channel}/`
+async fn channel_sessions(channel: web::Path<String>) -> Result<HttpResponse, Error> {
+    select(SessionFilter::Channel(Channel::create_unknown(channel.into_inner()))).await
+}
+
+/// `DELETE /admin/channels/{channel}/`
+async fn kick_channel(channel: web::Path<String>, query: web::Query<KickQuery>) -> Result<HttpResponse, Error> {
+    let reason = match query.close_reason() {
+        Ok(reason) => reason,
+        Err(error) => return Ok(bad_request(error)),
+    };
+
+    let filter = SessionFilter::Channel(Channel::create_unknown(channel.into_inner()));
+
+    Ok(HttpResponse::Ok().json(json!({ "disconnected": kick(filter, reason).await? })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_reason() {
+        let query = KickQuery { code: None, reason: None };
+        let reason = query.close_reason().unwrap();
+
+        assert_eq!(reason.code, CloseCode::Normal);
+        assert_eq!(reason.description.as_deref(), Some("Closed by admin"));
+
+        let query = KickQuery { code: Some(4001), reason: Some("Bye".to_string()) };
+        let reason = query.close_reason().unwrap();
+
+        assert_eq!(reason.code, CloseCode::Other(4001));
+        assert_eq!(reason.description.as_deref(), Some("Bye"));
+
+        for code in [1001, 1003, 1007, 1014, 3000, 4999] {
+            assert!(KickQuery { code: Some(code), reason: None }.close_reason().is_ok(), "{code}");
+        }
+
+        for code in [0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000] {
+            let error = KickQuery { code: Some(code), reason: None }.close_reason().unwrap_err();
+
+            assert!(error.starts_with("[EAD001]"), "{code}");
+        }
+    }
+}
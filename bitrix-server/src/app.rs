@@ -1,26 +1,44 @@
-use actix_web::http::header::{ContentType, HeaderValue};
+use std::time::Duration;
+
+use actix::Actor;
+use actix_web::error::InternalError;
+use actix_web::http::header::ContentType;
+use actix_web::middleware::from_fn;
 use actix_web::{web, Error, HttpRequest, HttpResponse, Responder};
 use actix_web_actors::ws;
 use futures_util::stream::StreamExt as _;
 use serde::{Deserialize, Serialize};
 use log::{debug, error};
+use tokio::sync::oneshot;
 
 use bitrix_channels::Parser;
-use actix_broker::{Broker, SystemBroker};
-use bitrix_actix_protobuf;
+use bitrix_actix_protobuf::{ProtoBuf, ProtoBufPayloadError, ProtoBufResponseBuilder};
 use bitrix_channels::Channel;
 
 
 use crate::{
+    access,
+    admin,
     utils,
     items,
-    message::{SendPullMessage, ProtobufMessage},
+    logging::{self, Redacted},
+    message::{ChannelMessage, ProtobufMessage},
+    metrics::{PublishPath, METRICS},
+    coalesce::Coalescer,
+    poll::{PollGuard, PollSession},
+    processor::{self, Origin},
+    protocol::{self, Protocol},
+    reload::Swap,
+    router::ShardRouter,
     session::WsSession,
+    settings::Settings,
 };
 
 /*
 Finally we need to get requests:
 
+Trusted requests pass `access::trusted`, see `[access]` in settings.
+
 POST /pub/ -> Application.publish. Trusted request.
 POST /pub/?binaryMode=true -> Application.processClientRequest. Trusted request.
 GET /pub/ -> Application.getChannelStats. Trusted request.
@@ -30,15 +48,29 @@ Client websocket -> Application.processClientRequest. Untrusted request.
 
 GET /server-stat/ -> Application.getServerStats. Trusted request.
 
+/admin/... -> admin API, see `admin`. Trusted requests, only with `[access]` restricted.
+
 GET /sub/ -> Application.subscribe. Long Polling requests.
 GET UPGRADE /sub/ -> Application.subscribe. Websocket requests.
 */
 
 pub fn routes_configure(cfg: &mut web::ServiceConfig) {
     /* Easy healthcheck */
-    cfg.service(web::resource("/").route(web::get().to(|| HttpResponse::Ok())))
+    cfg.service(web::resource("/").route(web::get().to(HttpResponse::Ok)))
         .service(web::scope("/bitrix")
-            .service(web::resource("/pub/").to(publication))
+            .service(web::resource("/pub/")
+                .wrap(from_fn(access::trusted))
+                .route(web::get().to(channel_stats))
+                .to(publication))
+            .service(web::resource("/rest/").route(web::post().to(rest)))
+            .service(web::resource("/server-stat/")
+                .wrap(from_fn(access::trusted))
+                .route(web::get().to(server_stats)))
+            .service(web::scope("/admin")
+                .wrap(from_fn(access::trusted))
+                .wrap(from_fn(access::restricted))
+                .configure(admin::routes_configure))
+            .service(web::resource("/sub/").route(web::get().to(sub_polling)))
             .service(web::resource("/subws/").to(sub_ws))
        );
 }
@@ -53,15 +85,20 @@ struct UnifiedQueryString {
     is_binary: Option<bool>,
     revision: Option<i32>,
     mid: Option<String>,
-    time: Option<i32>,
+    tag: Option<String>,
+    /* Echo of `Last-Modified`, an HTTP date */
+    time: Option<String>,
 }
 
 async fn publication(
     req: HttpRequest,
     mut payload: web::Payload,
     query: web::Query<UnifiedQueryString>,
-    parser: web::Data<Parser>
+    parser: web::Data<Swap<Parser>>,
+    router: web::Data<ShardRouter>
 ) -> Result<HttpResponse, Error> {
+    let parser = parser.load();
+
     if query.is_binary.is_some() {
         let requests_batch = bitrix_actix_protobuf::ProtoBufMessage::<items::RequestBatch>::new(
             &req,
@@ -72,6 +109,7 @@ async fn publication(
         let requests = match requests_batch {
             Ok(request_batch) => request_batch.requests,
             Err(error_kind) => {
+                METRICS.payload_rejected(&error_kind);
                 log::error!("Got binary that couldn't decode. Error: {error_kind}");
                 return Ok(HttpResponse::BadRequest()
                     .content_type(ContentType::plaintext())
@@ -79,77 +117,24 @@ async fn publication(
             }
         };
 
-        log::debug!("Parsed protobuf: {requests:?}");
+        log::debug!(request_id:% = logging::request_id(&req), request:% = Redacted(&requests); "Parsed protobuf");
 
-        for request in requests {
-            if request.command.is_none() {
-                log::warn!("Receive empty command");
-                continue;
-            }
+        /* Stats requests come the same way, only batches with messages are publishes */
+        let publishes = requests.iter().any(|request| {
+            matches!(
+                &request.command,
+                Some(items::request::Command::IncomingMessages(incoming)) if !incoming.messages.is_empty()
+            )
+        });
 
-            let request_command = request.command.unwrap();
-
-            match request_command {
-                items::request::Command::IncomingMessages(incoming_message_request) => {
-                    log::debug!("Process income messages request: {incoming_message_request:?}");
-
-                    for income_message in incoming_message_request.messages.into_iter() {
-                        log::debug!("Process income message request: {income_message:?}");
-
-                        let mut channel_ids = Vec::new();
-
-                        for receiver in income_message.receivers {
-                            match Channel::try_from(receiver.id) {
-                                Ok(channel) => {
-                                    channel_ids.push(channel);
-                                }
-                                Err(_) => continue,
-                            }
-                        }
-
-                        if channel_ids.is_empty() {
-                            continue;
-                        }
-
-                        let protobuf_message = items::ResponseBatch {
-                            responses: vec![items::Response {
-                                command: Some(items::response::Command::OutgoingMessages(
-                                    items::OutgoingMessagesResponse {
-                                        messages: vec![items::OutgoingMessage {
-                                            id: utils::get_message_id(),
-                                            body: income_message.body,
-                                            expiry: income_message.expiry,
-                                            created: 0,
-                                            sender: Some(items::Sender {
-                                                r#type: items::SenderType::Backend as i32,
-                                                id: vec![],
-                                            }),
-                                        }],
-                                    },
-                                )),
-                            }],
-                        };
+        if publishes {
+            METRICS.published(PublishPath::Binary);
+        }
 
-                        Broker::<SystemBroker>::issue_async(SendPullMessage(
-                            channel_ids,
-                            ProtobufMessage(protobuf_message),
-                        ));
-                    }
-                }
-                items::request::Command::ChannelStats(channel_stats_request) => {
-                    log::debug!("Process channel stats request: {channel_stats_request:?}");
-                }
-                items::request::Command::ServerStats(server_stats_request) => {
-                    log::debug!("Process server stats request: {server_stats_request:?}");
-                }
-                _ => {
-                    log::error!(
-                        "{}",
-                        format!("Got strange command: {:#?}. Ignore.", request_command).to_string()
-                    );
-                    continue;
-                }
-            }
+        let responses = processor::process_requests(requests, &Origin::Backend, &parser, &router).await?;
+
+        if !responses.is_empty() {
+            return HttpResponse::Ok().protobuf(items::ResponseBatch { responses });
         }
     } else {
         /* Trying to publish nonbinary message without channels */
@@ -164,7 +149,7 @@ async fn publication(
 
         let parse_channelds_result = parser.parse(channel_ids.clone());
 
-        log::trace!("Channels from request: {parse_channelds_result:?}");
+        log::trace!(request_id:% = logging::request_id(&req); "Channels from request: {parse_channelds_result:?}");
 
         if parse_channelds_result.is_err() {
             return Ok(HttpResponse::BadRequest()
@@ -178,24 +163,50 @@ async fn publication(
             bytes.extend_from_slice(&item?);
         }
 
-        log::debug!("Got push request {req:?},\r\n{bytes:?}");
+        let body = match String::from_utf8(bytes.to_vec()) {
+            Ok(body) => body,
+            Err(_) => {
+                return Ok(HttpResponse::BadRequest()
+                    .insert_header(("X-PUSH-ERR", "[EPR003] Message body is not UTF-8"))
+                    .content_type(ContentType::plaintext())
+                    .finish());
+            }
+        };
+
+        let expiry = match req.headers().get("message-expiry") {
+            None => 0,
+            Some(value) => match value.to_str().ok().and_then(|value| value.parse::<u32>().ok()) {
+                Some(expiry) => expiry,
+                None => {
+                    return Ok(HttpResponse::BadRequest()
+                        .insert_header(("X-PUSH-ERR", "[EPR004] Message expiry is not a number of seconds"))
+                        .content_type(ContentType::plaintext())
+                        .finish());
+                }
+            },
+        };
+
+        let message_id = utils::get_message_id();
+        let channels = parse_channelds_result.unwrap();
+
+        log::debug!(
+            request_id:% = logging::request_id(&req),
+            channels:% = logging::channel_ids(&channels),
+            message_id:% = utils::encode_message_id(&message_id),
+            sender = "backend",
+            body:% = Redacted(&bytes);
+            "Got push request"
+        );
 
         let protobuf_message = items::ResponseBatch {
             responses: vec![items::Response {
                 command: Some(items::response::Command::OutgoingMessages(
                     items::OutgoingMessagesResponse {
                         messages: vec![items::OutgoingMessage {
-                            id: utils::get_message_id(),
-                            body: std::str::from_utf8(&bytes).unwrap().to_string(),
-                            expiry: req
-                                .headers()
-                                .get("message-expiry")
-                                .unwrap_or(&HeaderValue::from_str("0")?)
-                                .to_str()
-                                .unwrap()
-                                .parse::<u32>()
-                                .unwrap(),
-                            created: 0,
+                            id: message_id,
+                            body,
+                            expiry,
+                            created: utils::get_timestamp(),
                             sender: Some(items::Sender {
                                 r#type: items::SenderType::Backend as i32,
                                 id: vec![],
@@ -206,10 +217,9 @@ async fn publication(
             }],
         };
 
-        Broker::<SystemBroker>::issue_async(SendPullMessage(
-            parse_channelds_result.unwrap(),
-            ProtobufMessage(protobuf_message),
-        ));
+        router.publish(channels, ProtobufMessage::new(protobuf_message));
+
+        METRICS.published(PublishPath::Text);
     }
 
     Ok(HttpResponse::Ok()
@@ -217,25 +227,87 @@ async fn publication(
         .finish())
 }
 
-async fn sub_ws(
-    req: HttpRequest,
-    stream: web::Payload,
+async fn channel_stats(
     query: web::Query<UnifiedQueryString>,
-    parser: web::Data<Parser>
-) -> Result<impl Responder, Error> {
+    parser: web::Data<Swap<Parser>>,
+    router: web::Data<ShardRouter>
+) -> Result<HttpResponse, Error> {
+    let parser = parser.load();
 
     if query.channel_ids.is_none() {
-        error!("Bad request, channel ids is empty!");
         return Ok(HttpResponse::BadRequest()
-            .insert_header(("X-PUSH-ERR", format!("[ES001] Channel ids empty")))
+            .insert_header(("X-PUSH-ERR", "[EPR001] Channel ids is missed"))
             .content_type(ContentType::plaintext())
-            .body("Channels is empty".to_string())
-        );
+            .finish());
     }
 
-    let channel_ids: String = query.channel_ids.as_ref().unwrap().clone();
+    let channels = match parser.parse(query.channel_ids.as_ref().unwrap().clone()) {
+        Ok(channels) => channels,
+        Err(error) => {
+            return Ok(HttpResponse::BadRequest()
+                .insert_header(("X-PUSH-ERR", format!("[EPR002] Channel ids parser error: {}", error)))
+                .content_type(ContentType::plaintext())
+                .finish());
+        }
+    };
+
+    HttpResponse::Ok().protobuf(items::ResponseBatch {
+        responses: vec![items::Response {
+            command: Some(items::response::Command::ChannelStats(
+                processor::get_channel_stats(&router, channels).await?,
+            )),
+        }],
+    })
+}
+
+async fn rest(
+    req: HttpRequest,
+    request_batch: Result<ProtoBuf<items::RequestBatch>, Error>,
+    parser: web::Data<Swap<Parser>>,
+    router: web::Data<ShardRouter>
+) -> Result<HttpResponse, Error> {
+    let request_batch = request_batch.inspect_err(|error| {
+        if let Some(payload_error) = error.as_error::<ProtoBufPayloadError>() {
+            METRICS.payload_rejected(payload_error);
+        }
+    })?;
 
-    let mut pull_session = WsSession::default();
+    log::debug!(request_id:% = logging::request_id(&req), request:% = Redacted(&request_batch); "Parsed protobuf");
+
+    let parser = parser.load();
+
+    let responses = processor::process_requests(
+        request_batch.0.requests,
+        &Origin::Client(None),
+        &parser,
+        &router,
+    )
+    .await?;
+
+    HttpResponse::Ok().protobuf(items::ResponseBatch { responses })
+}
+
+async fn server_stats(router: web::Data<ShardRouter>) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(processor::get_server_stats(&router).await?))
+}
+
+/// Parse and validate subscriber channels from `CHANNEL_ID`
+fn parse_subscriber_channels(
+    query: &UnifiedQueryString,
+    parser: &Parser,
+) -> Result<Vec<Channel>, Error> {
+    if query.channel_ids.is_none() {
+        error!("Bad request, channel ids is empty!");
+        return Err(InternalError::from_response(
+            "Channels is empty",
+            HttpResponse::BadRequest()
+                .insert_header(("X-PUSH-ERR", "[ES001] Channel ids empty"))
+                .content_type(ContentType::plaintext())
+                .body("Channels is empty".to_string())
+        ).into());
+    }
+
+    let channel_ids: String = query.channel_ids.as_ref().unwrap().clone();
 
     let parse_channelds_result = parser.parse(channel_ids.clone());
 
@@ -251,14 +323,380 @@ async fn sub_ws(
 
     if channels.is_empty() {
         error!("Couldn't parse channel ids. Channels empty.");
-        return Ok(HttpResponse::BadRequest()
-            .insert_header(("X-PUSH-ERR", format!("[ES002] Channel ids empty: {}", channel_ids.clone())))
-            .content_type(ContentType::plaintext())
-            .body("Channels is empty".to_string())
-        );
+        return Err(InternalError::from_response(
+            "Channels is empty",
+            HttpResponse::BadRequest()
+                .insert_header(("X-PUSH-ERR", format!("[ES002] Channel ids empty: {}", channel_ids.clone())))
+                .content_type(ContentType::plaintext())
+                .body("Channels is empty".to_string())
+        ).into());
     }
 
+    Ok(channels)
+}
+
+async fn sub_ws(
+    req: HttpRequest,
+    stream: web::Payload,
+    query: web::Query<UnifiedQueryString>,
+    parser: web::Data<Swap<Parser>>,
+    settings: web::Data<Swap<Settings>>,
+    router: web::Data<ShardRouter>
+) -> Result<impl Responder, Error> {
+    let settings = settings.load();
+
+    let channels = parse_subscriber_channels(&query, &parser.load())?;
+
+    let mut pull_session = WsSession::new(router.get_ref().clone());
+
     pull_session.set_channels(channels);
+    pull_session.set_remote_addr(req.peer_addr().map(|addr| addr.to_string()));
+    pull_session.set_parser(parser.into_inner());
+    pull_session.set_protocol(Protocol::negotiate(query.is_binary, query.revision));
+    pull_session.set_last_message_id(query.mid.as_deref().and_then(utils::decode_message_id));
+    pull_session.set_heartbeat(
+        Duration::from_secs(settings.websocket.heartbeat_interval),
+        Duration::from_secs(settings.websocket.client_timeout),
+    );
+    pull_session.set_batching(
+        settings.websocket.batch_max_size,
+        Duration::from_millis(settings.websocket.batch_max_delay),
+    );
 
     ws::start(pull_session, &req, stream)
-}
\ No newline at end of file
+}
+
+async fn sub_polling(
+    req: HttpRequest,
+    query: web::Query<UnifiedQueryString>,
+    parser: web::Data<Swap<Parser>>,
+    settings: web::Data<Swap<Settings>>,
+    router: web::Data<ShardRouter>
+) -> Result<HttpResponse, Error> {
+    let settings = settings.load();
+
+    let channels = parse_subscriber_channels(&query, &parser.load())?;
+
+    let last_message_id = query.mid.as_deref().and_then(utils::decode_message_id);
+
+    let (responder, waiter) = oneshot::channel();
+
+    let _session = PollGuard(PollSession::new(
+        router.get_ref().clone(),
+        channels,
+        Duration::from_secs(settings.polling.timeout),
+        last_message_id,
+        responder,
+        req.peer_addr().map(|addr| addr.to_string()),
+    )
+    .start());
+
+    let received = match waiter.await {
+        Ok(received) => received,
+        Err(_) => {
+            /* Nothing was published while we were waiting, client keeps its position */
+            let mut response = HttpResponse::NotModified();
+
+            if let Some(mid) = &query.mid {
+                response.insert_header(("Last-Message-Id", mid.clone()));
+            }
+            if let Some(tag) = &query.tag {
+                response.insert_header(("Etag", tag.clone()));
+            }
+            if let Some(time) = &query.time {
+                response.insert_header(("Last-Modified", time.clone()));
+            }
+
+            return Ok(response.finish());
+        }
+    };
+
+    /* Shards deliver in their own order, the newest is the one with the highest sequence */
+    let last_message = received
+        .iter()
+        .flat_map(|ChannelMessage(_, protobuf_msg)| protobuf_msg.sequenced())
+        .max_by_key(|(sequence, _)| *sequence);
+
+    let mut response = HttpResponse::Ok();
+
+    /* `Etag` is the `tag` of the text protocol, the history sequence */
+    if let Some((sequence, last_message)) = last_message {
+        response.insert_header(("Last-Message-Id", utils::encode_message_id(&last_message.id)));
+        response.insert_header(("Last-Modified", protocol::get_http_time(last_message.created)));
+        response.insert_header(("Etag", sequence.to_string()));
+    }
+
+    match Protocol::negotiate(query.is_binary, query.revision) {
+        Protocol::Binary => {
+            let mut frames = Coalescer::new(usize::MAX);
+
+            for ChannelMessage(_, protobuf_msg) in &received {
+                frames.push(protobuf_msg.frame(), 0);
+            }
+
+            Ok(response
+                .content_type("application/protobuf")
+                .body(frames.take().unwrap_or_default()))
+        }
+        Protocol::Text { with_mid } => {
+            let body = received
+                .iter()
+                .map(|ChannelMessage(channel, protobuf_msg)| protocol::encode_text(channel, protobuf_msg, with_mid))
+                .collect::<String>();
+
+            Ok(response.content_type(ContentType::plaintext()).body(body))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+    use prost::Message as _;
+
+    use super::*;
+    use crate::access::AccessPolicy;
+
+    const CHANNEL: &str = "f0e5d42369441879d7e176c96cbbff2d";
+
+    fn settings(polling_timeout: u64) -> Settings {
+        config::Config::builder()
+            .add_source(config::File::from_str(
+                &format!(
+                    "[general]\nport = 9099\nworkers = 1\n[security]\nenabled = false\nkey = \"secret\"\n[log]\nlevel = \"info\"\n[polling]\ntimeout = {polling_timeout}"
+                ),
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    fn message(body: &str) -> ProtobufMessage {
+        ProtobufMessage::new(items::ResponseBatch {
+            responses: vec![items::Response {
+                command: Some(items::response::Command::OutgoingMessages(
+                    items::OutgoingMessagesResponse {
+                        messages: vec![items::OutgoingMessage {
+                            id: vec![0xab],
+                            body: body.to_string(),
+                            created: utils::get_timestamp(),
+                            ..Default::default()
+                        }],
+                    },
+                )),
+            }],
+        })
+    }
+
+    macro_rules! app {
+        ($settings:expr, $router:expr) => {
+            init_service(
+                App::new()
+                    .app_data(web::Data::new(Swap::new($settings.security.parser())))
+                    .app_data(web::Data::new(Swap::new(
+                        AccessPolicy::new(&Default::default(), &$settings.security).unwrap(),
+                    )))
+                    .app_data(web::Data::new(Swap::new($settings)))
+                    .app_data(web::Data::new($router.clone()))
+                    .configure(routes_configure),
+            )
+            .await
+        };
+    }
+
+    #[actix_web::test]
+    async fn test_polling_delivers_messages() {
+        let router = ShardRouter::default();
+        let app = app!(settings(5), router);
+
+        let publish = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            for body in ["first", "second"] {
+                router.publish(vec![Channel::create_private(CHANNEL.to_string())], message(body));
+            }
+        };
+        let request = call_service(
+            &app,
+            TestRequest::get().uri(&format!("/bitrix/sub/?CHANNEL_ID={CHANNEL}")).to_request(),
+        );
+
+        let (response, _) = futures_util::future::join(request, publish).await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get("Last-Message-Id").unwrap(), "ab");
+        assert_eq!(response.headers().get("Etag").unwrap(), "2");
+
+        let body = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+
+        assert_eq!(body.matches("#!NGINXNMS!#").count(), 2);
+        assert!(body.contains("\"text\":\"first\"") && body.contains("\"text\":\"second\""));
+        assert!(body.contains("\"id\":1,") && body.contains("\"id\":2,"));
+    }
+
+    #[actix_web::test]
+    async fn test_polling_timeout_is_not_modified() {
+        let app = app!(settings(1), ShardRouter::default());
+
+        let response = call_service(
+            &app,
+            TestRequest::get()
+                .uri(&format!("/bitrix/sub/?CHANNEL_ID={CHANNEL}&mid=ab&tag=7"))
+                .to_request(),
+        )
+        .await;
+
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.headers().get("Last-Message-Id").unwrap(), "ab");
+        assert_eq!(response.headers().get("Etag").unwrap(), "7");
+    }
+
+    #[actix_web::test]
+    async fn test_publication_rejects_bad_text() {
+        let app = app!(settings(1), ShardRouter::default());
+
+        for (request, error) in [
+            (TestRequest::post().set_payload(vec![0xff, 0xfe]), "[EPR003] Message body is not UTF-8"),
+            (
+                TestRequest::post().insert_header(("message-expiry", "soon")).set_payload("text"),
+                "[EPR004] Message expiry is not a number of seconds",
+            ),
+        ] {
+            let response = call_service(
+                &app,
+                request.uri(&format!("/bitrix/pub/?CHANNEL_ID={CHANNEL}")).to_request(),
+            )
+            .await;
+
+            assert_eq!(response.status(), 400);
+            assert_eq!(response.headers().get("X-PUSH-ERR").unwrap(), error);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_admin_needs_restricted_access() {
+        use crate::settings::Access;
+
+        let settings = settings(1);
+
+        for (access, status) in [
+            (Access::default(), 403),
+            (Access { allow: vec!["10.0.0.0/8".to_string()], ..Default::default() }, 200),
+        ] {
+            let policy = AccessPolicy::new(&access, &settings.security).unwrap();
+            let app = init_service(
+                App::new()
+                    .app_data(web::Data::new(Swap::new(policy)))
+                    .app_data(web::Data::new(ShardRouter::default()))
+                    .configure(routes_configure),
+            )
+            .await;
+
+            let response = call_service(
+                &app,
+                TestRequest::get()
+                    .uri("/bitrix/admin/sessions/")
+                    .peer_addr("10.0.0.1:5000".parse().unwrap())
+                    .to_request(),
+            )
+            .await;
+
+            assert_eq!(response.status(), status);
+        }
+
+        /* No `[access]` at all is the default config too */
+        let app = app!(settings, ShardRouter::default());
+        let response = call_service(&app, TestRequest::get().uri("/bitrix/admin/sessions/").to_request()).await;
+
+        assert_eq!(response.status(), 403);
+        assert_eq!(
+            response.headers().get("X-PUSH-ERR").unwrap(),
+            "[EAC005] Needs [access] allow or require_signature"
+        );
+    }
+
+    /// `POST /bitrix/rest/` publishing `body` to `CHANNEL` with the receiver `signature`
+    fn rest_publish(body: &str, signature: Vec<u8>) -> TestRequest {
+        let batch = items::RequestBatch {
+            requests: vec![items::Request {
+                command: Some(items::request::Command::IncomingMessages(
+                    items::IncomingMessagesRequest {
+                        messages: vec![items::IncomingMessage {
+                            receivers: vec![items::Receiver {
+                                id: utils::decode_hex(CHANNEL).unwrap(),
+                                is_private: true,
+                                signature,
+                            }],
+                            body: body.to_string(),
+                            ..Default::default()
+                        }],
+                    },
+                )),
+            }],
+        };
+
+        TestRequest::post()
+            .uri("/bitrix/rest/")
+            .insert_header(("Content-Type", "application/x-protobuf"))
+            .set_payload(batch.encode_to_vec())
+    }
+
+    fn receiver_signature() -> Vec<u8> {
+        let digest = bitrix_channels::Signature::new("secret".to_string()).get_digest(CHANNEL.to_string());
+
+        utils::decode_hex(&digest).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_rest_publishes_to_signed_receivers() {
+        let app = app!(settings(5), ShardRouter::default());
+
+        let publish = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            call_service(&app, rest_publish("signed", receiver_signature()).to_request()).await
+        };
+        let request = call_service(
+            &app,
+            TestRequest::get().uri(&format!("/bitrix/sub/?CHANNEL_ID={CHANNEL}")).to_request(),
+        );
+
+        let (response, published) = futures_util::future::join(request, publish).await;
+
+        assert_eq!(published.status(), 200);
+        assert_eq!(response.status(), 200);
+
+        let body = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+
+        assert!(body.contains("\"text\":\"signed\""), "{body}");
+    }
+
+    #[actix_web::test]
+    async fn test_rest_skips_bad_and_missing_signatures() {
+        let app = app!(settings(1), ShardRouter::default());
+
+        let mut bad_signature = receiver_signature();
+        bad_signature[0] ^= 0xff;
+
+        let publish = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            for signature in [bad_signature, vec![]] {
+                let published = call_service(&app, rest_publish("forged", signature).to_request()).await;
+
+                assert_eq!(published.status(), 200);
+            }
+        };
+        let request = call_service(
+            &app,
+            TestRequest::get().uri(&format!("/bitrix/sub/?CHANNEL_ID={CHANNEL}")).to_request(),
+        );
+
+        let (response, _) = futures_util::future::join(request, publish).await;
+
+        assert_eq!(response.status(), 304);
+    }
+}
@@ -0,0 +1,562 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use prost::Message;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use bitrix_channels::{Channel, ChannelType, Signature};
+
+use crate::{
+    items,
+    message::ProtobufMessage,
+    router::ShardRouter,
+    utils,
+};
+
+/// Frames waiting for a peer that is not connected yet
+const PEER_QUEUE_SIZE: usize = 1024;
+const PEER_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// Biggest `NotificationBatch` frame we agree to read from a peer
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+/// Random bytes a connected peer has to sign with the security key
+const NONCE_SIZE: usize = 16;
+/// HMAC-SHA1
+const DIGEST_SIZE: usize = 20;
+/// Keeps handshake signatures apart from channel and request signatures made with the same key
+const HANDSHAKE_PREFIX: &[u8] = b"push-mesh:";
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Fan-out of publishes between push-server instances.
+///
+/// `ShardRouter` delivers every publish to its own subscribers and hands it
+/// to the backend. Publishes of other instances go to `router`, which only
+/// delivers them locally.
+pub trait PubSubBackend: Send + Sync {
+    fn start(&mut self, router: ShardRouter);
+
+    fn publish(&self, channels: &[Channel], msg: &ProtobufMessage);
+}
+
+/// Single instance, every subscriber lives in this process
+#[derive(Default)]
+pub struct InProcessBackend;
+
+impl PubSubBackend for InProcessBackend {
+    fn start(&mut self, _router: ShardRouter) {}
+
+    fn publish(&self, _channels: &[Channel], _msg: &ProtobufMessage) {}
+}
+
+/// Full mesh of instances over TCP.
+///
+/// Every instance listens for peers and connects to each address in `peers`.
+/// Frames are `NotificationBatch` with `IpcMessages`, prefixed by big endian
+/// `u32` length. Instances forward only their own publishes, so the mesh must
+/// list every other instance.
+///
+/// Connections are accepted only from the addresses of `peers`, and only after
+/// the peer signs a random nonce with the security key.
+pub struct TcpMeshBackend {
+    listener: Option<std::net::TcpListener>,
+    peers: Vec<String>,
+    signature: Signature,
+    queues: Vec<mpsc::Sender<Vec<u8>>>,
+}
+
+impl TcpMeshBackend {
+    pub fn bind(listen: &str, peers: Vec<String>, signature: Signature) -> io::Result<Self> {
+        if signature.get_key().is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "[cluster] needs [security] key to authenticate peers",
+            ));
+        }
+
+        let listener = std::net::TcpListener::bind(listen)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(TcpMeshBackend {
+            listener: Some(listener),
+            peers,
+            signature,
+            queues: Vec::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.listener {
+            Some(listener) => listener.local_addr(),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "Listener is already started")),
+        }
+    }
+
+    async fn accept(
+        listener: TcpListener,
+        peers: Arc<[String]>,
+        signature: Signature,
+        router: ShardRouter,
+    ) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, address)) => {
+                    actix::spawn(Self::read_peer(stream, address, peers.clone(), signature.clone(), router.clone()));
+                }
+                Err(error) => log::error!("TcpMeshBackend::accept => {error}"),
+            }
+        }
+    }
+
+    async fn read_peer(
+        mut stream: TcpStream,
+        address: SocketAddr,
+        peers: Arc<[String]>,
+        signature: Signature,
+        router: ShardRouter,
+    ) {
+        /* Checked here rather than in `accept`, a slow resolver holds up this connection only */
+        if !is_peer(address.ip(), &peers).await {
+            log::warn!("TcpMeshBackend::read_peer => {address} is not a peer, refused");
+            return;
+        }
+
+        if let Err(error) = challenge(&mut stream, &signature).await {
+            log::warn!("TcpMeshBackend::read_peer => {address} failed the handshake: {error}");
+            return;
+        }
+
+        log::debug!("TcpMeshBackend::read_peer => peer {address} connected");
+
+        loop {
+            let frame = match read_frame(&mut stream).await {
+                Ok(frame) => frame,
+                Err(error) => {
+                    log::debug!("TcpMeshBackend::read_peer => peer gone: {error}");
+                    return;
+                }
+            };
+
+            for (channels, protobuf_msg) in decode_notifications(&frame) {
+                router.deliver(channels, protobuf_msg);
+            }
+        }
+    }
+
+    async fn write_peer(address: String, signature: Signature, mut queue: mpsc::Receiver<Vec<u8>>) {
+        let mut frame: Option<Vec<u8>> = None;
+
+        loop {
+            let mut stream = match TcpStream::connect(&address).await {
+                Ok(stream) => stream,
+                Err(error) => {
+                    log::debug!("TcpMeshBackend::write_peer => {address}: {error}");
+                    actix::clock::sleep(PEER_RECONNECT_INTERVAL).await;
+                    continue;
+                }
+            };
+
+            if let Err(error) = answer(&mut stream, &signature).await {
+                log::warn!("TcpMeshBackend::write_peer => {address} handshake: {error}");
+                actix::clock::sleep(PEER_RECONNECT_INTERVAL).await;
+                continue;
+            }
+
+            log::info!("TcpMeshBackend::write_peer => connected to {address}");
+
+            loop {
+                if frame.is_none() {
+                    frame = match queue.recv().await {
+                        Some(frame) => Some(frame),
+                        None => return,
+                    };
+                }
+
+                let body = frame.as_ref().unwrap();
+
+                let write_result = async {
+                    stream.write_u32(body.len() as u32).await?;
+                    stream.write_all(body).await
+                }
+                .await;
+
+                if let Err(error) = write_result {
+                    log::warn!("TcpMeshBackend::write_peer => {address}: {error}");
+                    break;
+                }
+
+                frame = None;
+            }
+        }
+    }
+}
+
+impl PubSubBackend for TcpMeshBackend {
+    fn start(&mut self, router: ShardRouter) {
+        let listener = match self.listener.take().map(TcpListener::from_std) {
+            Some(Ok(listener)) => listener,
+            Some(Err(error)) => {
+                log::error!("TcpMeshBackend::start => {error}");
+                return;
+            }
+            None => return,
+        };
+
+        actix::spawn(Self::accept(listener, self.peers.clone().into(), self.signature.clone(), router));
+
+        for address in self.peers.iter() {
+            let (sender, receiver) = mpsc::channel(PEER_QUEUE_SIZE);
+            self.queues.push(sender);
+            actix::spawn(Self::write_peer(address.clone(), self.signature.clone(), receiver));
+        }
+    }
+
+    fn publish(&self, channels: &[Channel], msg: &ProtobufMessage) {
+        let frame = match encode_notifications(channels, msg) {
+            Some(frame) => frame,
+            None => return,
+        };
+
+        for queue in self.queues.iter() {
+            if let Err(error) = queue.try_send(frame.clone()) {
+                log::warn!("TcpMeshBackend::publish => peer queue: {error}");
+            }
+        }
+    }
+}
+
+/// Only instances listed in `peers` may connect, host names are resolved on every connection
+async fn is_peer(ip: IpAddr, peers: &[String]) -> bool {
+    for peer in peers {
+        match tokio::net::lookup_host(peer).await {
+            Ok(mut addresses) => {
+                if addresses.any(|address| address.ip() == ip) {
+                    return true;
+                }
+            }
+            Err(error) => log::debug!("TcpMeshBackend::is_peer => {peer}: {error}"),
+        }
+    }
+
+    false
+}
+
+/// Accepting side of the handshake: the peer must sign our nonce with the security key
+async fn challenge(stream: &mut TcpStream, signature: &Signature) -> io::Result<()> {
+    let nonce = rand::random::<[u8; NONCE_SIZE]>();
+    stream.write_all(&nonce).await?;
+
+    let mut digest = [0; DIGEST_SIZE];
+    handshake_timeout(stream.read_exact(&mut digest)).await?;
+
+    match signature.verify_bytes(&[HANDSHAKE_PREFIX, &nonce].concat(), &digest) {
+        true => Ok(()),
+        false => Err(io::Error::new(io::ErrorKind::PermissionDenied, "Signature mismatch")),
+    }
+}
+
+/// Connecting side of the handshake
+async fn answer(stream: &mut TcpStream, signature: &Signature) -> io::Result<()> {
+    let mut nonce = [0; NONCE_SIZE];
+    handshake_timeout(stream.read_exact(&mut nonce)).await?;
+
+    stream
+        .write_all(&signature.get_raw_digest(&[HANDSHAKE_PREFIX, &nonce].concat()))
+        .await
+}
+
+async fn handshake_timeout<T>(read: impl std::future::Future<Output = io::Result<T>>) -> io::Result<T> {
+    actix::clock::timeout(HANDSHAKE_TIMEOUT, read)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Handshake timed out"))?
+}
+
+async fn read_frame(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let length = stream.read_u32().await? as usize;
+
+    if length > MAX_FRAME_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Frame is too big"));
+    }
+
+    let mut frame = vec![0; length];
+    stream.read_exact(&mut frame).await?;
+
+    Ok(frame)
+}
+
+fn encode_notifications(channels: &[Channel], msg: &ProtobufMessage) -> Option<Vec<u8>> {
+    let receivers = channels
+        .iter()
+        .filter_map(|channel| {
+            Some(items::Receiver {
+                id: Vec::<u8>::try_from(channel).ok()?,
+                is_private: channel.get_kind() == ChannelType::Private,
+                signature: vec![],
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if receivers.is_empty() {
+        return None;
+    }
+
+    let messages = utils::get_outgoing_messages(msg.batch())
+        .map(|outgoing_message| items::IpcMessage {
+            receivers: receivers.clone(),
+            outgoing_message_id: outgoing_message.id.clone(),
+            outgoing_message: Some(outgoing_message.clone()),
+        })
+        .collect::<Vec<_>>();
+
+    if messages.is_empty() {
+        return None;
+    }
+
+    let notification_batch = items::NotificationBatch {
+        notifications: vec![items::Notification {
+            command: Some(items::notification::Command::IpcMessages(items::IpcMessages {
+                messages,
+            })),
+        }],
+    };
+
+    Some(notification_batch.encode_to_vec())
+}
+
+/// Publishes of a peer frame, one per message
+fn decode_notifications(frame: &[u8]) -> Vec<(Vec<Channel>, ProtobufMessage)> {
+    let notification_batch = match items::NotificationBatch::decode(frame) {
+        Ok(notification_batch) => notification_batch,
+        Err(error) => {
+            log::error!("TcpMeshBackend => couldn't decode peer frame: {error}");
+            return Vec::new();
+        }
+    };
+
+    notification_batch
+        .notifications
+        .into_iter()
+        .filter_map(|notification| match notification.command {
+            Some(items::notification::Command::IpcMessages(ipc_messages)) => Some(ipc_messages.messages),
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|ipc_message| {
+            let channels = ipc_message
+                .receivers
+                .into_iter()
+                .filter_map(|receiver| {
+                    let channel = Channel::try_from(receiver.id).ok()?;
+
+                    Some(match receiver.is_private {
+                        true => Channel::create_private(channel.to_string()),
+                        false => Channel::create_public(channel.to_string()),
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let outgoing_message = ipc_message.outgoing_message?;
+
+            Some((
+                channels,
+                ProtobufMessage::new(items::ResponseBatch {
+                    responses: vec![items::Response {
+                        command: Some(items::response::Command::OutgoingMessages(
+                            items::OutgoingMessagesResponse {
+                                messages: vec![outgoing_message],
+                            },
+                        )),
+                    }],
+                }),
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::prelude::*;
+
+    use super::*;
+    use crate::{
+        message::{ChannelMessage, Client, DisconnectMessage},
+        server::WsPullServer,
+        settings,
+    };
+
+    struct Collector(mpsc::UnboundedSender<ChannelMessage>);
+
+    impl Actor for Collector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<ChannelMessage> for Collector {
+        type Result = ();
+
+        fn handle(&mut self, msg: ChannelMessage, _ctx: &mut Self::Context) {
+            self.0.send(msg).unwrap();
+        }
+    }
+
+    impl Handler<DisconnectMessage> for Collector {
+        type Result = ();
+
+        fn handle(&mut self, _msg: DisconnectMessage, ctx: &mut Self::Context) {
+            ctx.stop();
+        }
+    }
+
+    /// Router of an instance with one subscriber to `channels`
+    fn collector(channels: &[Channel]) -> (ShardRouter, mpsc::UnboundedReceiver<ChannelMessage>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let router = ShardRouter::new(
+            WsPullServer::default().start(),
+            &settings::History::default(),
+            Box::new(InProcessBackend),
+            2,
+            settings::Backpressure::default(),
+        );
+
+        router.subscribe(channels.to_vec(), Client::new(Collector(sender).start()), None);
+
+        (router, receiver)
+    }
+
+    fn message(body: &str) -> ProtobufMessage {
+        ProtobufMessage::new(items::ResponseBatch {
+            responses: vec![items::Response {
+                command: Some(items::response::Command::OutgoingMessages(
+                    items::OutgoingMessagesResponse {
+                        messages: vec![items::OutgoingMessage {
+                            id: vec![1, 2, 3],
+                            body: body.to_string(),
+                            ..Default::default()
+                        }],
+                    },
+                )),
+            }],
+        })
+    }
+
+    fn body(msg: &ProtobufMessage) -> String {
+        match &msg.batch().responses[0].command {
+            Some(items::response::Command::OutgoingMessages(outgoing)) => {
+                outgoing.messages[0].body.clone()
+            }
+            _ => panic!("Not an outgoing message"),
+        }
+    }
+
+    async fn receive(receiver: &mut mpsc::UnboundedReceiver<ChannelMessage>) -> ChannelMessage {
+        actix::clock::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .expect("Peer didn't get the message")
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_notifications_round_trip() {
+        let channels = vec![
+            Channel::create_private("f0e5d42369441879d7e176c96cbbff2d".to_string()),
+            Channel::create_public("c18beb389c3e49131dbb2dde597df615".to_string()),
+        ];
+
+        let frame = encode_notifications(&channels, &message("hello")).unwrap();
+        let decoded = decode_notifications(&frame);
+
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].0, channels);
+        assert_eq!(body(&decoded[0].1), "hello");
+    }
+
+    #[actix_web::test]
+    async fn test_notifications_skip_non_hex_channels() {
+        let channels = vec![Channel::create_private("not a hex".to_string())];
+
+        assert!(encode_notifications(&channels, &message("hello")).is_none());
+    }
+
+    fn signature(key: &str) -> Signature {
+        Signature::new(key.to_string())
+    }
+
+    #[actix_web::test]
+    async fn test_mesh_fan_out_between_instances() {
+        let channels = vec![Channel::create_private("f0e5d42369441879d7e176c96cbbff2d".to_string())];
+
+        let mut second = TcpMeshBackend::bind("127.0.0.1:0", vec![], signature("secret")).unwrap();
+        let mut third = TcpMeshBackend::bind("127.0.0.1:0", vec![], signature("secret")).unwrap();
+        let mut first = TcpMeshBackend::bind(
+            "127.0.0.1:0",
+            vec![
+                second.local_addr().unwrap().to_string(),
+                third.local_addr().unwrap().to_string(),
+            ],
+            signature("secret"),
+        )
+        .unwrap();
+
+        /* Lets first connect, the listeners are bound before anybody knows the others */
+        second.peers = vec![first.local_addr().unwrap().to_string()];
+        third.peers = second.peers.clone();
+
+        let (first_router, mut first_received) = collector(&channels);
+        let (second_router, mut second_received) = collector(&channels);
+        let (third_router, mut third_received) = collector(&channels);
+
+        first.start(first_router);
+        second.start(second_router);
+        third.start(third_router);
+
+        first.publish(&channels, &message("hello"));
+
+        let second_message = receive(&mut second_received).await;
+        let third_message = receive(&mut third_received).await;
+
+        assert_eq!(second_message.0, channels[0]);
+        assert_eq!(body(&second_message.1), "hello");
+        assert_eq!(body(&third_message.1), "hello");
+        assert!(first_received.try_recv().is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_mesh_refuses_unauthenticated_peers() {
+        let channels = vec![Channel::create_private("f0e5d42369441879d7e176c96cbbff2d".to_string())];
+
+        let mut instance = TcpMeshBackend::bind("127.0.0.1:0", vec![], signature("secret")).unwrap();
+        let address = instance.local_addr().unwrap().to_string();
+        instance.peers = vec!["127.0.0.1:1".to_string()];
+
+        let (router, mut received) = collector(&channels);
+        instance.start(router);
+
+        /* Right address, wrong key */
+        let mut stranger = TcpMeshBackend::bind("127.0.0.1:0", vec![address.clone()], signature("guess")).unwrap();
+        stranger.start(collector(&channels).0);
+        stranger.publish(&channels, &message("forged"));
+
+        /* No handshake at all */
+        let frame = encode_notifications(&channels, &message("raw")).unwrap();
+        let mut stream = TcpStream::connect(&address).await.unwrap();
+        stream.write_u32(frame.len() as u32).await.unwrap();
+        stream.write_all(&frame).await.unwrap();
+
+        assert!(actix::clock::timeout(Duration::from_millis(300), received.recv()).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_is_peer() {
+        let localhost = "127.0.0.1".parse().unwrap();
+
+        assert!(is_peer(localhost, &["10.0.0.2:9100".to_string(), "127.0.0.1:9100".to_string()]).await);
+        assert!(!is_peer(localhost, &["10.0.0.2:9100".to_string()]).await);
+        assert!(!is_peer(localhost, &[]).await);
+    }
+
+    #[test]
+    fn test_cluster_needs_key() {
+        assert!(TcpMeshBackend::bind("127.0.0.1:0", vec![], Signature::default()).is_err());
+    }
+}
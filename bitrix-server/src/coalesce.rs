@@ -0,0 +1,119 @@
+use bytes::{Bytes, BytesMut};
+
+/// Binary frames waiting to go to one subscriber as a single frame.
+///
+/// Concatenated protobuf messages decode as one message with the repeated
+/// fields merged, so pending `ResponseBatch` frames are joined without
+/// decoding or encoding them again.
+#[derive(Default)]
+pub struct Coalescer {
+    max_messages: usize,
+    frames: Vec<Bytes>,
+    messages: usize,
+}
+
+impl Coalescer {
+    pub fn new(max_messages: usize) -> Self {
+        Coalescer {
+            max_messages: max_messages.max(1),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Queue a frame with `messages` outgoing messages, `true` once the batch is full
+    pub fn push(&mut self, frame: Bytes, messages: usize) -> bool {
+        self.frames.push(frame);
+        self.messages += messages;
+
+        self.messages >= self.max_messages
+    }
+
+    /// Everything queued as one `ResponseBatch` frame
+    pub fn take(&mut self) -> Option<Bytes> {
+        self.messages = 0;
+
+        match self.frames.len() {
+            0 => None,
+            1 => self.frames.pop(),
+            _ => {
+                let mut merged = BytesMut::with_capacity(self.frames.iter().map(Bytes::len).sum());
+
+                for frame in self.frames.drain(..) {
+                    merged.extend_from_slice(&frame);
+                }
+
+                Some(merged.freeze())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+    use crate::{items, message::ProtobufMessage, utils};
+
+    fn frame(ids: &[u8]) -> Bytes {
+        ProtobufMessage::new(items::ResponseBatch {
+            responses: vec![items::Response {
+                command: Some(items::response::Command::OutgoingMessages(
+                    items::OutgoingMessagesResponse {
+                        messages: ids
+                            .iter()
+                            .map(|id| items::OutgoingMessage {
+                                id: vec![*id],
+                                ..Default::default()
+                            })
+                            .collect(),
+                    },
+                )),
+            }],
+        })
+        .frame()
+    }
+
+    #[test]
+    fn test_merged_frame_is_one_batch() {
+        let mut coalescer = Coalescer::new(10);
+
+        assert!(!coalescer.push(frame(&[1]), 1));
+        assert!(!coalescer.push(frame(&[2, 3]), 2));
+
+        let merged = items::ResponseBatch::decode(coalescer.take().unwrap()).unwrap();
+
+        assert_eq!(
+            utils::get_outgoing_messages(&merged).map(|message| message.id[0]).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert!(coalescer.is_empty());
+        assert!(coalescer.take().is_none());
+    }
+
+    #[test]
+    fn test_single_frame_goes_as_is() {
+        let mut coalescer = Coalescer::new(10);
+        let single = frame(&[1]);
+
+        coalescer.push(single.clone(), 1);
+
+        assert_eq!(coalescer.take().unwrap().as_ptr(), single.as_ptr());
+    }
+
+    #[test]
+    fn test_full_batch() {
+        let mut coalescer = Coalescer::new(3);
+
+        assert!(!coalescer.push(frame(&[1, 2]), 2));
+        assert!(coalescer.push(frame(&[3]), 1));
+
+        coalescer.take();
+
+        assert!(!coalescer.push(frame(&[4]), 1));
+    }
+}
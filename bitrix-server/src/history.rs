@@ -0,0 +1,281 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use bitrix_channels::Channel;
+
+use crate::{items, utils};
+
+/// Message with the sequence number `ShardRouter` gave it on publish
+pub type Entry = (u64, items::OutgoingMessage);
+
+/// Bounded per-channel buffer of recently published messages.
+///
+/// Every stored message keeps the sequence number it was published with, the
+/// same in every channel and shard, so a replay over several channels keeps
+/// publish order. At most `max_channels` channels are kept, the least recently
+/// published channel is forgotten first.
+#[derive(Default)]
+pub struct History {
+    size: usize,
+    max_channels: usize,
+    channels: HashMap<String, VecDeque<Entry>>,
+    /// Sequence of the newest message of every channel, oldest first
+    recent: BTreeSet<(u64, String)>,
+}
+
+impl History {
+    pub fn new(size: usize, max_channels: usize) -> Self {
+        History {
+            size,
+            max_channels,
+            ..Default::default()
+        }
+    }
+
+    /// `sequence` is the number of the first message, the next ones follow it
+    pub fn push(&mut self, sequence: u64, channels: &[Channel], messages: &[items::OutgoingMessage]) {
+        if self.size == 0 {
+            return;
+        }
+
+        let now = utils::get_timestamp();
+
+        for (sequence, message) in (sequence..).zip(messages) {
+            if utils::is_expired(message, now) {
+                continue;
+            }
+
+            for channel in channels {
+                let channel = channel.to_string();
+                let buffer = self.channels.entry(channel.clone()).or_default();
+
+                if let Some((newest, _)) = buffer.back() {
+                    self.recent.remove(&(*newest, channel.clone()));
+                }
+
+                if buffer.len() >= self.size {
+                    buffer.pop_front();
+                }
+
+                buffer.push_back((sequence, message.clone()));
+                self.recent.insert((sequence, channel));
+            }
+        }
+
+        while self.channels.len() > self.max_channels {
+            let Some((_, channel)) = self.recent.pop_first() else {
+                break;
+            };
+
+            self.channels.remove(&channel);
+        }
+    }
+
+    /// Everything kept for `channels`, a message published to several of them comes once per channel
+    pub fn entries(&self, channels: &[Channel]) -> Vec<Entry> {
+        channels
+            .iter()
+            .filter_map(|channel| self.channels.get(&channel.to_string()))
+            .flat_map(|buffer| buffer.iter().cloned())
+            .collect()
+    }
+
+    /// Forget expired messages and channels with nothing left in them
+    pub fn purge_expired(&mut self, now: u32) {
+        self.channels.retain(|_, buffer| {
+            buffer.retain(|(_, message)| !utils::is_expired(message, now));
+            !buffer.is_empty()
+        });
+
+        self.recent = self
+            .channels
+            .iter()
+            .filter_map(|(channel, buffer)| Some((buffer.back()?.0, channel.clone())))
+            .collect();
+    }
+}
+
+/// Sequence of the message with `id`, `None` when it is not in `entries` anymore (or never was)
+pub fn sequence_of(entries: &[Entry], id: &[u8]) -> Option<u64> {
+    entries
+        .iter()
+        .find(|(_, message)| message.id == id)
+        .map(|(sequence, _)| *sequence)
+}
+
+/// Entries published after `last_sequence`, in publish order.
+///
+/// `entries` may come from several channels, a message published to several
+/// of them is returned once. Messages expired by `now` are never returned.
+pub fn after(mut entries: Vec<Entry>, last_sequence: u64, now: u32) -> Vec<Entry> {
+    entries.retain(|(sequence, message)| *sequence > last_sequence && !utils::is_expired(message, now));
+    entries.sort_by_key(|(sequence, _)| *sequence);
+    entries.dedup_by_key(|(sequence, _)| *sequence);
+
+    entries
+}
+
+/// Messages after the one with `last_message_id`, in publish order.
+///
+/// `entries` may come from several shards. Returns `None` when the id is not
+/// in them anymore (or never was), so the caller can't tell what the client
+/// missed. Messages expired by `now` are never returned.
+pub fn since(entries: Vec<Entry>, last_message_id: &[u8], now: u32) -> Option<Vec<items::OutgoingMessage>> {
+    let last_sequence = sequence_of(&entries, last_message_id)?;
+
+    Some(after(entries, last_sequence, now).into_iter().map(|(_, message)| message).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u8) -> items::OutgoingMessage {
+        items::OutgoingMessage {
+            id: vec![id],
+            body: format!("message {id}"),
+            ..Default::default()
+        }
+    }
+
+    fn expiring_message(id: u8, created: u32, expiry: u32) -> items::OutgoingMessage {
+        items::OutgoingMessage {
+            created,
+            expiry,
+            ..message(id)
+        }
+    }
+
+    fn ids(messages: Vec<items::OutgoingMessage>) -> Vec<u8> {
+        messages.into_iter().map(|message| message.id[0]).collect()
+    }
+
+    #[test]
+    fn test_replay_after_message_id() {
+        let channels = vec![Channel::create_private("abc".to_string())];
+        let mut history = History::new(10, 10);
+
+        history.push(1, &channels, &[message(1), message(2), message(3)]);
+
+        assert_eq!(ids(since(history.entries(&channels), &[1], 0).unwrap()), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_replay_is_bounded() {
+        let channels = vec![Channel::create_private("abc".to_string())];
+        let mut history = History::new(2, 10);
+
+        history.push(1, &channels, &[message(1), message(2), message(3)]);
+
+        assert!(since(history.entries(&channels), &[1], 0).is_none());
+        assert_eq!(ids(since(history.entries(&channels), &[2], 0).unwrap()), vec![3]);
+    }
+
+    #[test]
+    fn test_replay_merges_channels_in_publish_order() {
+        let private = vec![Channel::create_private("abc".to_string())];
+        let public = vec![Channel::create_public("def".to_string())];
+        let both = [private.clone(), public.clone()].concat();
+        let mut history = History::new(10, 10);
+
+        history.push(1, &private, &[message(1)]);
+        history.push(2, &public, &[message(2)]);
+        history.push(3, &both, &[message(3)]);
+        history.push(4, &private, &[message(4)]);
+
+        assert_eq!(ids(since(history.entries(&both), &[1], 0).unwrap()), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn test_replay_merges_shards() {
+        let private = vec![Channel::create_private("abc".to_string())];
+        let public = vec![Channel::create_public("def".to_string())];
+        let mut first = History::new(10, 10);
+        let mut second = History::new(10, 10);
+
+        first.push(1, &private, &[message(1)]);
+        second.push(2, &public, &[message(2)]);
+        first.push(3, &private, &[message(3)]);
+        second.push(3, &public, &[message(3)]);
+
+        let entries = [second.entries(&public), first.entries(&private)].concat();
+
+        assert_eq!(ids(since(entries, &[1], 0).unwrap()), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_replay_unknown_message_id() {
+        let channels = vec![Channel::create_private("abc".to_string())];
+        let mut history = History::new(10, 10);
+
+        history.push(1, &channels, &[message(1)]);
+
+        assert!(since(history.entries(&channels), &[42], 0).is_none());
+    }
+
+    #[test]
+    fn test_zero_size_keeps_nothing() {
+        let channels = vec![Channel::create_private("abc".to_string())];
+        let mut history = History::new(0, 10);
+
+        history.push(1, &channels, &[message(1), message(2)]);
+
+        assert!(since(history.entries(&channels), &[1], 0).is_none());
+    }
+
+    #[test]
+    fn test_expired_messages_are_not_stored() {
+        let channels = vec![Channel::create_private("abc".to_string())];
+        let mut history = History::new(10, 10);
+
+        history.push(1, &channels, &[message(1), expiring_message(2, 1, 1), message(3)]);
+
+        assert_eq!(ids(since(history.entries(&channels), &[1], 0).unwrap()), vec![3]);
+    }
+
+    #[test]
+    fn test_expired_messages_are_not_replayed() {
+        let channels = vec![Channel::create_private("abc".to_string())];
+        let now = utils::get_timestamp();
+        let mut history = History::new(10, 10);
+
+        history.push(1, &channels, &[message(1), expiring_message(2, now, 10), message(3)]);
+
+        assert_eq!(ids(since(history.entries(&channels), &[1], now).unwrap()), vec![2, 3]);
+        assert_eq!(ids(since(history.entries(&channels), &[1], now + 10).unwrap()), vec![3]);
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        let private = vec![Channel::create_private("abc".to_string())];
+        let public = vec![Channel::create_public("def".to_string())];
+        let now = utils::get_timestamp();
+        let mut history = History::new(10, 10);
+
+        history.push(1, &private, &[expiring_message(1, now, 10)]);
+        history.push(2, &public, &[message(2), expiring_message(3, now, 10)]);
+
+        history.purge_expired(now + 10);
+
+        assert!(!history.channels.contains_key("abc"));
+        assert_eq!(history.channels.get("def").map(|buffer| buffer.len()), Some(1));
+        assert_eq!(history.recent.len(), 1);
+    }
+
+    #[test]
+    fn test_least_recently_published_channel_is_dropped() {
+        let first = vec![Channel::create_private("abc".to_string())];
+        let second = vec![Channel::create_private("def".to_string())];
+        let third = vec![Channel::create_private("ghi".to_string())];
+        let mut history = History::new(10, 2);
+
+        history.push(1, &first, &[message(1)]);
+        history.push(2, &second, &[message(2)]);
+        history.push(3, &first, &[message(3)]);
+        history.push(4, &third, &[message(4)]);
+
+        assert_eq!(history.channels.len(), 2);
+        assert!(since(history.entries(&second), &[2], 0).is_none());
+        assert_eq!(ids(since(history.entries(&first), &[1], 0).unwrap()), vec![3]);
+        assert_eq!(history.recent.len(), 2);
+    }
+}
@@ -0,0 +1,228 @@
+use actix::prelude::*;
+use actix_files::Files;
+use actix_web::http::header::ContentType;
+use actix_web::middleware::from_fn;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use bitrix_channels::Channel;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    access, items,
+    message::TapMessage,
+    router::ShardRouter,
+    utils,
+};
+
+/*
+Live traffic page, only with `[inspector] enabled`. Trusted requests from `[access] allow`.
+
+GET /inspector/ -> the built-in page, or the one from `[inspector] static_dir`.
+GET UPGRADE /inspector/tap/ -> every delivered publish as a `TapEvent` text frame.
+*/
+
+const INDEX_PAGE: &str = include_str!("../static/inspector/index.html");
+
+pub fn routes_configure(cfg: &mut web::ServiceConfig, static_dir: Option<&str>) {
+    let scope = web::scope("/inspector")
+        .wrap(from_fn(access::trusted))
+        .wrap(from_fn(access::allowlisted))
+        .service(web::resource("/tap/").to(tap));
+
+    cfg.service(match static_dir {
+        Some(static_dir) => scope.service(Files::new("/", static_dir).index_file("index.html")),
+        None => scope.service(web::resource("/").to(index)),
+    });
+}
+
+/// `GET /inspector/`
+async fn index() -> HttpResponse {
+    HttpResponse::Ok().content_type(ContentType::html()).body(INDEX_PAGE)
+}
+
+/// One publish as the inspector page shows it
+#[derive(Serialize, Debug)]
+pub struct TapEvent {
+    /// Unix time of delivery
+    pub time: u32,
+    pub channels: Vec<String>,
+    pub messages: Vec<TapOutgoingMessage>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TapOutgoingMessage {
+    pub id: String,
+    /// Parsed when the body is JSON, the text as is otherwise
+    pub body: Value,
+    pub expiry: u32,
+    pub created: u32,
+    pub sender: TapSender,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TapSender {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub id: String,
+}
+
+impl TapEvent {
+    pub fn new(channels: &[Channel], batch: &items::ResponseBatch) -> Self {
+        TapEvent {
+            time: utils::get_timestamp(),
+            channels: channels.iter().map(ToString::to_string).collect(),
+            messages: utils::get_outgoing_messages(batch)
+                .map(|message| TapOutgoingMessage {
+                    id: utils::encode_message_id(&message.id),
+                    body: serde_json::from_str(&message.body)
+                        .unwrap_or_else(|_| Value::String(message.body.clone())),
+                    expiry: message.expiry,
+                    created: message.created,
+                    sender: message
+                        .sender
+                        .as_ref()
+                        .map(|sender| TapSender {
+                            kind: items::SenderType::from_i32(sender.r#type)
+                                .unwrap_or(items::SenderType::Unknown)
+                                .as_str_name(),
+                            id: utils::encode_hex(&sender.id),
+                        })
+                        .unwrap_or(TapSender {
+                            kind: items::SenderType::Unknown.as_str_name(),
+                            id: String::new(),
+                        }),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Inspector page connection, only receives `TapMessage`s
+struct TapSession(ShardRouter);
+
+impl Actor for TapSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        log::debug!("Inspector connected");
+
+        self.0.tap(ctx.address().recipient());
+    }
+}
+
+impl Handler<TapMessage> for TapSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: TapMessage, ctx: &mut Self::Context) {
+        ctx.text(msg.0);
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for TapSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Err(_) => ctx.stop(),
+            _ => {}
+        }
+    }
+}
+
+/// `GET UPGRADE /inspector/tap/`
+async fn tap(req: HttpRequest, stream: web::Payload, router: web::Data<ShardRouter>) -> Result<HttpResponse, Error> {
+    ws::start(TapSession(router.get_ref().clone()), &req, stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tap_event() {
+        let batch = items::ResponseBatch {
+            responses: vec![items::Response {
+                command: Some(items::response::Command::OutgoingMessages(
+                    items::OutgoingMessagesResponse {
+                        messages: vec![
+                            items::OutgoingMessage {
+                                id: vec![0xab, 0xcd],
+                                body: r#"{"module_id":"im","params":{"id":1}}"#.to_string(),
+                                expiry: 60,
+                                created: 1700000000,
+                                sender: Some(items::Sender {
+                                    r#type: items::SenderType::Backend as i32,
+                                    id: vec![],
+                                }),
+                            },
+                            items::OutgoingMessage {
+                                body: "plain text".to_string(),
+                                ..Default::default()
+                            },
+                        ],
+                    },
+                )),
+            }],
+        };
+        let channels = vec![Channel::create_private("f0e5d42369441879d7e176c96cbbff2d".to_string())];
+
+        let event = serde_json::to_value(TapEvent::new(&channels, &batch)).unwrap();
+
+        assert_eq!(event["channels"][0], "f0e5d42369441879d7e176c96cbbff2d");
+        assert_eq!(event["messages"][0]["id"], "abcd");
+        assert_eq!(event["messages"][0]["body"]["params"]["id"], 1);
+        assert_eq!(event["messages"][0]["expiry"], 60);
+        assert_eq!(event["messages"][0]["sender"]["type"], "BACKEND");
+        assert_eq!(event["messages"][1]["body"], "plain text");
+        assert_eq!(event["messages"][1]["sender"]["type"], "UNKNOWN");
+    }
+
+    #[actix_web::test]
+    async fn test_page_needs_allowlist() {
+        use actix_web::test::{call_service, init_service, read_body, TestRequest};
+        use actix_web::App;
+
+        use crate::{
+            access::AccessPolicy,
+            reload::Swap,
+            settings::{Access, Security},
+        };
+
+        let security = Security {
+            enabled: true,
+            key: "test".to_string(),
+        };
+
+        for (access, status) in [
+            (Access::default(), 403),
+            (Access { require_signature: true, ..Default::default() }, 403),
+            (Access { allow: vec!["10.0.0.0/8".to_string()], ..Default::default() }, 200),
+        ] {
+            let policy = AccessPolicy::new(&access, &security).unwrap();
+            let app = init_service(
+                App::new()
+                    .app_data(web::Data::new(Swap::new(policy)))
+                    .configure(|cfg| routes_configure(cfg, None)),
+            )
+            .await;
+
+            let response = call_service(
+                &app,
+                TestRequest::get()
+                    .uri("/inspector/")
+                    .peer_addr("10.0.0.1:5000".parse().unwrap())
+                    .to_request(),
+            )
+            .await;
+
+            assert_eq!(response.status().as_u16(), status);
+            if status == 200 {
+                assert_eq!(read_body(response).await, INDEX_PAGE);
+            }
+        }
+    }
+}
@@ -0,0 +1,31 @@
+pub mod access;
+pub mod admin;
+pub mod app;
+pub mod backend;
+pub mod coalesce;
+pub mod history;
+pub mod inspector;
+pub mod logging;
+pub mod message;
+pub mod metrics;
+pub mod poll;
+pub mod processor;
+pub mod protocol;
+pub mod reload;
+pub mod router;
+pub mod server;
+pub mod session;
+pub mod settings;
+pub mod shard;
+pub mod shutdown;
+pub mod stats;
+pub mod tls;
+pub mod utils;
+
+#[allow(clippy::derive_partial_eq_without_eq, dead_code)]
+pub mod items;
+/*pub mod items {
+    include!("proto.rs");
+    //include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+*/
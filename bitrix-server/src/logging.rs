@@ -0,0 +1,272 @@
+use std::fmt;
+use std::io::{self, Write as _};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage, HttpRequest};
+use bitrix_channels::Channel;
+use env_logger::{fmt::Formatter, Builder, Env};
+use log::kv::{self, VisitSource};
+use log::{Log, Metadata, Record};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+use crate::{
+    items,
+    settings::{self, LogFormat},
+    utils,
+};
+
+/// Message bodies go to the log only with `[log] debug_bodies`
+static LOG_BODIES: AtomicBool = AtomicBool::new(false);
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// env_logger with filters and format replaced on reload, `RUST_LOG` wins over `[log] level`
+pub struct Logger(RwLock<env_logger::Logger>);
+
+impl Logger {
+    pub fn init(settings: &settings::Log) -> &'static Logger {
+        let logger: &'static Logger = Box::leak(Box::new(Logger(RwLock::new(build_logger(settings)))));
+
+        log::set_logger(logger).expect("Logger is already set");
+        log::set_max_level(logger.0.read().unwrap().filter());
+
+        logger
+    }
+
+    pub fn set(&self, settings: &settings::Log) {
+        let logger = build_logger(settings);
+
+        log::set_max_level(logger.filter());
+        *self.0.write().unwrap() = logger;
+    }
+}
+
+/// `RUST_LOG` is set, `[log] level` is ignored then
+pub fn level_from_env() -> bool {
+    std::env::var_os(env_logger::DEFAULT_FILTER_ENV).is_some()
+}
+
+fn build_logger(settings: &settings::Log) -> env_logger::Logger {
+    LOG_BODIES.store(settings.debug_bodies, Ordering::Relaxed);
+
+    let mut builder = Builder::from_env(Env::default().default_filter_or(&settings.level));
+
+    match settings.format {
+        LogFormat::Text => builder.format(format_text),
+        LogFormat::Json => builder.format(format_json),
+    };
+
+    builder.build()
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.read().unwrap().log(record)
+    }
+
+    fn flush(&self) {
+        self.0.read().unwrap().flush()
+    }
+}
+
+/// env_logger's own layout with `key=value` fields at the end
+fn format_text(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    write!(
+        buf,
+        "[{} {:<5} {}] {}",
+        buf.timestamp(),
+        buf.default_styled_level(record.level()),
+        record.target(),
+        record.args()
+    )?;
+
+    for (key, value) in fields(record) {
+        match value {
+            Value::String(value) => write!(buf, " {key}={value}")?,
+            value => write!(buf, " {key}={value}")?,
+        }
+    }
+
+    writeln!(buf)
+}
+
+/// One JSON object per line, fields next to `msg`
+fn format_json(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let mut line = fields(record);
+
+    line.insert("ts".to_string(), buf.timestamp().to_string().into());
+    line.insert("level".to_string(), record.level().as_str().into());
+    line.insert("target".to_string(), record.target().into());
+    line.insert("msg".to_string(), record.args().to_string().into());
+
+    serde_json::to_writer(&mut *buf, &line)?;
+
+    writeln!(buf)
+}
+
+fn fields(record: &Record) -> Map<String, Value> {
+    let mut fields = Fields(Map::new());
+
+    let _ = record.key_values().visit(&mut fields);
+
+    fields.0
+}
+
+struct Fields(Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(value) = value.to_bool() {
+            value.into()
+        } else if let Some(value) = value.to_i64() {
+            value.into()
+        } else if let Some(value) = value.to_u64() {
+            value.into()
+        } else {
+            value.to_string().into()
+        };
+
+        self.0.insert(key.to_string(), value);
+
+        Ok(())
+    }
+}
+
+/// Logs as `{:?}` of the value with `[log] debug_bodies`, as a placeholder otherwise
+pub struct Redacted<'a, T>(pub &'a T);
+
+impl<T: fmt::Debug> fmt::Display for Redacted<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match LOG_BODIES.load(Ordering::Relaxed) {
+            true => write!(f, "{:?}", self.0),
+            false => f.write_str("<redacted>"),
+        }
+    }
+}
+
+/// Channel ids for a log field, `/` separated like in `CHANNEL_ID`
+pub fn channel_ids(channels: &[Channel]) -> String {
+    channels
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Hex ids of the outgoing messages of a batch, `,` separated
+pub fn message_ids(batch: &items::ResponseBatch) -> String {
+    utils::get_outgoing_messages(batch)
+        .map(|message| utils::encode_message_id(&message.id))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Id of the HTTP request, taken from `X-Request-Id` or made up
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Request id of `req`, empty outside of the `assign_request_id` middleware
+pub fn request_id(req: &HttpRequest) -> String {
+    req.extensions()
+        .get::<RequestId>()
+        .map(|request_id| request_id.0.clone())
+        .unwrap_or_default()
+}
+
+/// Middleware giving every request an id and echoing it in `X-Request-Id`
+pub async fn assign_request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= 128)
+        .map(ToString::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().simple().to_string());
+
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let mut response = next.call(req).await?;
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static("x-request-id"), value);
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fields() {
+        let session = Uuid::nil();
+        let body = Redacted(&"secret");
+        let pairs: [(&str, kv::Value); 4] = [
+            ("session", kv::Value::from_display(&session)),
+            ("count", 3.into()),
+            ("private", true.into()),
+            ("body", kv::Value::from_display(&body)),
+        ];
+
+        let fields = fields(&Record::builder().key_values(&pairs).build());
+
+        assert_eq!(fields["session"], "00000000-0000-0000-0000-000000000000");
+        assert_eq!(fields["count"], 3);
+        assert_eq!(fields["private"], true);
+        assert_eq!(fields["body"], "<redacted>");
+    }
+
+    #[test]
+    fn test_channel_ids() {
+        let channels = vec![
+            Channel::create_private("f0e5d42369441879d7e176c96cbbff2d".to_string()),
+            Channel::create_private("3c8264bab589b0de7174e7b0523a40db".to_string()),
+        ];
+
+        assert_eq!(
+            channel_ids(&channels),
+            "f0e5d42369441879d7e176c96cbbff2d/3c8264bab589b0de7174e7b0523a40db"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_request_id() {
+        use actix_web::test::{call_service, init_service, TestRequest};
+        use actix_web::{middleware::from_fn, web, App, HttpResponse};
+
+        let app = init_service(App::new().wrap(from_fn(assign_request_id)).route(
+            "/",
+            web::get().to(|req: HttpRequest| async move { HttpResponse::Ok().body(request_id(&req)) }),
+        ))
+        .await;
+
+        let response = call_service(
+            &app,
+            TestRequest::get().uri("/").insert_header((REQUEST_ID_HEADER, "abc")).to_request(),
+        )
+        .await;
+
+        assert_eq!(response.headers().get(REQUEST_ID_HEADER).unwrap(), "abc");
+        assert_eq!(actix_web::test::read_body(response).await, "abc");
+
+        let response = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+
+        assert_eq!(response.headers().get(REQUEST_ID_HEADER).unwrap().len(), 32);
+    }
+}
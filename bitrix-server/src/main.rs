@@ -1,56 +1,158 @@
-use actix_web::{middleware::Logger, web, App, HttpServer};
-use bitrix_channels::{Parser, Signature};
+use actix::{Actor, SystemRegistry};
+use actix_web::{middleware::{from_fn, Logger}, web, App, HttpServer};
 use log::{info, debug};
 use std::env;
-mod app;
-mod message;
-mod server;
-mod session;
-mod settings;
-mod utils;
-
-use settings::Settings;
-
-#[allow(clippy::derive_partial_eq_without_eq)]
-mod items;
-/*mod items {
-    include!("proto.rs");
-    //include!(concat!(env!("OUT_DIR"), "/_.rs"));
-}
-*/
+
+use bitrix_channels::Signature;
+use bitrix_server::{
+    access::AccessPolicy,
+    app,
+    backend::{InProcessBackend, PubSubBackend, TcpMeshBackend},
+    inspector,
+    logging,
+    metrics,
+    reload::{self, Reloader, Swap},
+    router::ShardRouter,
+    server::WsPullServer,
+    settings::Settings,
+    shutdown,
+    tls,
+};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let settings = Settings::new().expect("Parse settings error");
 
-    if env::var("RUST_LOG").ok().is_none() {
-        env::set_var("RUST_LOG", settings.log.level.clone());
-    }
-
-    env_logger::init();
+    let logger = logging::Logger::init(&settings.log);
 
     info!(
-        "starting HTTP server at http://0.0.0.0:{}",
+        "starting HTTP server at {}://0.0.0.0:{}",
+        if settings.tls.is_some() { "https" } else { "http" },
         settings.general.port
     );
 
-    info!("log level set to {}", env::var("RUST_LOG").unwrap());
+    info!("log level set to {}", env::var("RUST_LOG").unwrap_or(settings.log.level.clone()));
 
-    let parser = match settings.security.enabled {
-        true => Parser::new(true, Signature::new(settings.security.key.clone())),
-        false => Parser::default(),
-    };
+    let parser = settings.security.parser();
 
     debug!("security parser is {}", parser.get_status());
 
-    HttpServer::new(move || {
+    let access = AccessPolicy::new(&settings.access, &settings.security)
+        .expect("Parse access settings error");
+
+    if !access.is_restricted() {
+        info!("admin API is off until [access] has allow or require_signature");
+    }
+
+    let backend: Box<dyn PubSubBackend> = match &settings.cluster.listen {
+        Some(listen) => {
+            let backend = TcpMeshBackend::bind(
+                listen,
+                settings.cluster.peers.clone(),
+                Signature::new(settings.security.key.clone()),
+            )?;
+            info!(
+                "cluster listens at {}, peers {:?}",
+                backend.local_addr()?,
+                settings.cluster.peers
+            );
+            Box::new(backend)
+        }
+        None => Box::new(InProcessBackend),
+    };
+
+    let sessions = WsPullServer::default().start();
+    SystemRegistry::set(sessions.clone());
+
+    let router = web::Data::new(ShardRouter::new(
+        sessions,
+        &settings.history,
+        backend,
+        settings.general.shards,
+        settings.backpressure.clone(),
+    ));
+
+    /* Shared by all workers, swapped on SIGHUP */
+    let reloader = Reloader {
+        settings: web::Data::new(Swap::new(settings.clone())),
+        parser: web::Data::new(Swap::new(parser)),
+        access: web::Data::new(Swap::new(access)),
+        logger,
+    };
+
+    let (app_settings, app_parser, app_access, app_router) =
+        (reloader.settings.clone(), reloader.parser.clone(), reloader.access.clone(), router.clone());
+
+    let inspector = settings.inspector.enabled && reloader.access.load().has_allowlist();
+    let inspector_dir = settings.inspector.static_dir.clone();
+
+    if settings.inspector.enabled && !inspector {
+        log::error!("inspector stays off until [access] has allow");
+    } else if inspector {
+        info!("inspector enabled at /inspector/, page from {}", inspector_dir.as_deref().unwrap_or("the binary"));
+    }
+
+    let server = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(parser.clone()))
+            .app_data(app_parser.clone())
+            .app_data(app_settings.clone())
+            .app_data(app_access.clone())
+            .app_data(app_router.clone())
             .configure(app::routes_configure)
-            .wrap(Logger::default())
+            .configure(|cfg| {
+                if inspector {
+                    inspector::routes_configure(cfg, inspector_dir.as_deref());
+                }
+            })
+            .wrap(from_fn(logging::assign_request_id))
+            .wrap(Logger::new(r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T request_id=%{X-Request-Id}o"#))
     })
     .workers(settings.general.workers)
-    .bind(("0.0.0.0", settings.general.port))?
-    .run()
-    .await
+    .shutdown_timeout(settings.general.shutdown_timeout)
+    /* Handled by `shutdown::on_signal` to close sessions properly */
+    .disable_signals();
+
+    let server = match &settings.tls {
+        Some(tls_settings) => {
+            let (tls_config, certificates) = tls::server_config(tls_settings)?;
+            tls::reload_on_sighup(certificates)?;
+
+            info!("TLS enabled with certificate {}", tls_settings.cert_path);
+
+            server.bind_rustls_0_23(("0.0.0.0", settings.general.port), tls_config)?
+        }
+        None => server.bind(("0.0.0.0", settings.general.port))?,
+    };
+
+    if let Some(listen) = &settings.metrics.listen {
+        let metrics_access = reloader.access.clone();
+        let metrics_router = router.clone();
+
+        let metrics_server = HttpServer::new(move || {
+            App::new()
+                .app_data(metrics_access.clone())
+                .app_data(metrics_router.clone())
+                .configure(metrics::routes_configure)
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(listen)?
+        .run();
+
+        info!("metrics at http://{listen}/metrics");
+
+        actix::spawn(async move {
+            if let Err(error) = metrics_server.await {
+                log::error!("metrics server failed: {error}");
+            }
+        });
+    }
+
+    reload::reload_on_sighup(reloader)?;
+
+    let server = server.run();
+
+    shutdown::on_signal(server.handle())?;
+
+    server.await
 }
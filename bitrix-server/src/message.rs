@@ -1,15 +1,368 @@
-use crate::items;
-use actix::{Message, Recipient};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::{
+    history, items,
+    stats::{ServerStats, SessionStats, ShardStats},
+    utils,
+};
+use actix::{dev::ToEnvelope, prelude::SendError, Actor, Addr, Handler, Message, Recipient};
+use actix_web_actors::ws::CloseReason;
 use bitrix_channels::Channel;
+use bytes::Bytes;
+use prost::Message as _;
+use serde::Serialize;
+use uuid::Uuid;
+
+/// Published batch, encoded once.
+///
+/// Clones share both the batch and the binary frame, so fan-out to thousands
+/// of subscribers neither copies nor re-encodes the messages.
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct ProtobufMessage {
+    batch: Arc<items::ResponseBatch>,
+    frame: Bytes,
+    published: Instant,
+    /// History sequence of every outgoing message, empty until `ShardRouter` publishes it
+    sequences: Arc<[u64]>,
+}
+
+impl ProtobufMessage {
+    pub fn new(batch: items::ResponseBatch) -> Self {
+        ProtobufMessage {
+            frame: Bytes::from(batch.encode_to_vec()),
+            batch: Arc::new(batch),
+            published: Instant::now(),
+            sequences: Arc::new([]),
+        }
+    }
+
+    /// Outgoing messages numbered from `first` on, in batch order
+    pub fn with_sequence(self, first: u64) -> Self {
+        let messages = utils::get_outgoing_messages(&self.batch).count() as u64;
+
+        ProtobufMessage {
+            sequences: (first..first + messages).collect(),
+            ..self
+        }
+    }
+
+    /// History entries as one batch, keeping their sequences
+    pub fn replayed(entries: Vec<history::Entry>) -> Self {
+        let (sequences, messages): (Vec<_>, Vec<_>) = entries.into_iter().unzip();
+
+        ProtobufMessage {
+            sequences: sequences.into(),
+            ..ProtobufMessage::new(items::ResponseBatch {
+                responses: vec![items::Response {
+                    command: Some(items::response::Command::OutgoingMessages(
+                        items::OutgoingMessagesResponse { messages },
+                    )),
+                }],
+            })
+        }
+    }
+
+    pub fn batch(&self) -> &items::ResponseBatch {
+        &self.batch
+    }
+
+    /// `ResponseBatch` in protobuf, ready to go to a binary subscriber
+    pub fn frame(&self) -> Bytes {
+        self.frame.clone()
+    }
+
+    /// When the message entered this server, for the delivery latency
+    pub fn published(&self) -> Instant {
+        self.published
+    }
+
+    /// Same message without the ones expired by `now`, `None` when nothing is left.
+    /// The batch is encoded again only if something has actually expired.
+    pub fn without_expired(&self, now: u32) -> Option<Self> {
+        self.without(|message| utils::is_expired(message, now))
+    }
+
+    /// Outgoing messages with their history sequences, 0 for a batch never published
+    pub fn sequenced(&self) -> impl Iterator<Item = (u64, &items::OutgoingMessage)> {
+        self.sequences
+            .iter()
+            .copied()
+            .chain(std::iter::repeat(0))
+            .zip(utils::get_outgoing_messages(&self.batch))
+    }
+
+    /// Same message without the ones matching `filter`, `None` when nothing is left
+    pub fn without(&self, filter: impl Fn(&items::OutgoingMessage) -> bool) -> Option<Self> {
+        let matched = utils::get_outgoing_messages(&self.batch).any(&filter);
+
+        if !matched {
+            return Some(self.clone());
+        }
+
+        let sequences = self
+            .sequenced()
+            .filter(|(_, message)| !filter(message))
+            .map(|(sequence, _)| sequence)
+            .collect();
+
+        let mut batch = items::ResponseBatch::clone(&self.batch);
+
+        match utils::retain_messages(&mut batch, |message| !filter(message)) {
+            true => Some(ProtobufMessage {
+                published: self.published,
+                sequences,
+                ..ProtobufMessage::new(batch)
+            }),
+            false => None,
+        }
+    }
+}
+
+impl From<items::ResponseBatch> for ProtobufMessage {
+    fn from(batch: items::ResponseBatch) -> Self {
+        ProtobufMessage::new(batch)
+    }
+}
 
+/// Batch delivered to a subscriber of the channel
 #[derive(Clone, Message)]
 #[rtype(result = "()")]
-pub struct ProtobufMessage(pub items::ResponseBatch);
+pub struct ChannelMessage(pub Channel, pub ProtobufMessage);
 
+/// Ask a subscriber to close its connection
 #[derive(Clone, Message)]
 #[rtype(result = "()")]
-pub struct SubscribeChannelMessage(pub Vec<Channel>, pub Recipient<ProtobufMessage>);
+pub struct DisconnectMessage(pub CloseReason);
+
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionKind {
+    #[default]
+    Unknown,
+    #[serde(rename = "websocket")]
+    WebSocket,
+    Polling,
+}
+
+/// Connection details shared between a session and the `Client`s made of it
+#[derive(Debug, Default)]
+pub struct SessionInfo {
+    pub id: Uuid,
+    pub kind: SessionKind,
+    pub remote_addr: Option<String>,
+    /// Outgoing messages written to the connection
+    pub sent: AtomicU64,
+}
+
+impl SessionInfo {
+    pub fn new(kind: SessionKind, remote_addr: Option<String>) -> Self {
+        SessionInfo {
+            id: Uuid::new_v4(),
+            kind,
+            remote_addr,
+            sent: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add_sent(&self, messages: usize) {
+        self.sent.fetch_add(messages as u64, Ordering::Relaxed);
+    }
+}
+
+/// Subscriber as shards see it, compared by its `ChannelMessage` recipient
+#[derive(Clone, Debug)]
+pub struct Client {
+    messages: Recipient<ChannelMessage>,
+    control: Recipient<DisconnectMessage>,
+    info: Arc<SessionInfo>,
+}
+
+impl Client {
+    pub fn new<A>(address: Addr<A>) -> Self
+    where
+        A: Actor + Handler<ChannelMessage> + Handler<DisconnectMessage>,
+        A::Context: ToEnvelope<A, ChannelMessage> + ToEnvelope<A, DisconnectMessage>,
+    {
+        Client {
+            messages: address.clone().recipient(),
+            control: address.recipient(),
+            info: Arc::new(SessionInfo::new(SessionKind::Unknown, None)),
+        }
+    }
+
+    pub fn with_info(self, info: Arc<SessionInfo>) -> Self {
+        Client { info, ..self }
+    }
+
+    pub fn info(&self) -> &SessionInfo {
+        &self.info
+    }
+
+    pub fn try_send(&self, msg: ChannelMessage) -> Result<(), SendError<ChannelMessage>> {
+        self.messages.try_send(msg)
+    }
+
+    pub fn connected(&self) -> bool {
+        self.messages.connected()
+    }
+
+    /// Goes past the mailbox capacity, a full mailbox is usually the reason to disconnect
+    pub fn disconnect(&self, reason: CloseReason) {
+        self.control.do_send(DisconnectMessage(reason));
+    }
+}
+
+impl PartialEq for Client {
+    fn eq(&self, other: &Self) -> bool {
+        self.messages == other.messages
+    }
+}
+
+impl Eq for Client {}
+
+impl Hash for Client {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.messages.hash(state);
+    }
+}
+
+/// Subscribe to channels of one `WsPullShard`
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct SubscribeChannelMessage(
+    pub Vec<Channel>,
+    pub Client,
+    /// History sequence of the last message the client has seen, everything after it is replayed
+    pub Option<u64>,
+);
+
+/// History sequence of the message with the id in channels of one `WsPullShard`
+#[derive(Clone, Message)]
+#[rtype(result = "Option<u64>")]
+pub struct HistorySequenceMessage(pub Vec<Channel>, pub Vec<u8>);
 
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct UnsubscribeChannelMessage(pub Vec<Channel>, pub Client);
+
+/// Publish to channels of one `WsPullShard`, the message is already sequenced
 #[derive(Clone, Message)]
 #[rtype(result = "()")]
 pub struct SendPullMessage(pub Vec<Channel>, pub ProtobufMessage);
+
+/// Session subscribed, `WsPullServer` keeps it for the admin API and shutdown
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct RegisterSessionMessage(pub Client, pub Vec<Channel>);
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct UnregisterSessionMessage(pub Client);
+
+/// Online status for every channel, in the same order
+#[derive(Clone, Message)]
+#[rtype(result = "Vec<bool>")]
+pub struct ChannelStatsMessage(pub Vec<Channel>);
+
+#[derive(Clone, Message)]
+#[rtype(result = "ServerStats")]
+pub struct ServerStatsMessage;
+
+/// Sessions an admin request is about
+#[derive(Clone, Debug)]
+pub enum SessionFilter {
+    All,
+    Id(Uuid),
+    Channel(Channel),
+}
+
+/// Live sessions matching the filter
+#[derive(Clone, Message)]
+#[rtype(result = "Vec<SessionStats>")]
+pub struct SessionsMessage(pub SessionFilter);
+
+/// Close the sessions matching the filter, returns how many were asked to
+#[derive(Clone, Message)]
+#[rtype(result = "usize")]
+pub struct KickMessage(pub SessionFilter, pub CloseReason);
+
+/// Publish as JSON for the inspector page, see `inspector::TapEvent`
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct TapMessage(pub String);
+
+/// Close every session with the reason and turn away new ones, returns how many were closed
+#[derive(Clone, Message)]
+#[rtype(result = "usize")]
+pub struct ShutdownMessage(pub CloseReason);
+
+#[derive(Clone, Message)]
+#[rtype(result = "ShardStats")]
+pub struct ShardStatsMessage;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u8, created: u32, expiry: u32) -> items::OutgoingMessage {
+        items::OutgoingMessage {
+            id: vec![id],
+            created,
+            expiry,
+            ..Default::default()
+        }
+    }
+
+    fn protobuf_message(messages: Vec<items::OutgoingMessage>) -> ProtobufMessage {
+        ProtobufMessage::new(items::ResponseBatch {
+            responses: vec![items::Response {
+                command: Some(items::response::Command::OutgoingMessages(
+                    items::OutgoingMessagesResponse { messages },
+                )),
+            }],
+        })
+    }
+
+    #[test]
+    fn test_frame_is_encoded_batch() {
+        let msg = protobuf_message(vec![message(1, 0, 0)]);
+
+        assert_eq!(items::ResponseBatch::decode(msg.frame()).unwrap(), *msg.batch());
+    }
+
+    #[test]
+    fn test_clones_share_frame() {
+        let msg = protobuf_message(vec![message(1, 0, 0)]);
+        let clone = msg.clone();
+
+        assert_eq!(msg.frame().as_ptr(), clone.frame().as_ptr());
+        assert!(Arc::ptr_eq(&msg.batch, &clone.batch));
+    }
+
+    #[test]
+    fn test_without_expired_keeps_frame_when_nothing_expired() {
+        let msg = protobuf_message(vec![message(1, 100, 10), message(2, 0, 0)]);
+
+        let filtered = msg.without_expired(105).unwrap();
+
+        assert_eq!(msg.frame().as_ptr(), filtered.frame().as_ptr());
+    }
+
+    #[test]
+    fn test_without_expired_encodes_again() {
+        let msg = protobuf_message(vec![message(1, 100, 10), message(2, 0, 0)]);
+
+        let filtered = msg.without_expired(110).unwrap();
+        let decoded = items::ResponseBatch::decode(filtered.frame()).unwrap();
+
+        assert_eq!(
+            utils::get_outgoing_messages(&decoded).map(|message| message.id[0]).collect::<Vec<_>>(),
+            vec![2]
+        );
+        assert!(protobuf_message(vec![message(1, 100, 10)]).without_expired(110).is_none());
+    }
+}
@@ -0,0 +1,226 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+use actix_web::middleware::from_fn;
+use actix_web::{web, Error, HttpResponse};
+use bitrix_actix_protobuf::ProtoBufPayloadError;
+
+use crate::{access, processor, router::ShardRouter, stats::ServerStats};
+
+/// Counters not owned by any actor, the shards keep the rest
+pub static METRICS: Metrics = Metrics::new();
+
+/// Upper bounds of the publish to deliver latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+const PAYLOAD_ERRORS: [&str; 5] = ["overflow", "content_type", "serialize", "deserialize", "payload"];
+
+/// How a backend published a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublishPath {
+    /// `POST /pub/?CHANNEL_ID=...` with the message text as the body
+    Text,
+    /// `POST /pub/?binaryMode=true` with a `RequestBatch`
+    Binary,
+}
+
+pub struct Metrics {
+    websocket_sessions: AtomicI64,
+    text_publishes: AtomicU64,
+    binary_publishes: AtomicU64,
+    payload_errors: [AtomicU64; PAYLOAD_ERRORS.len()],
+    latency: Histogram,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            websocket_sessions: AtomicI64::new(0),
+            text_publishes: AtomicU64::new(0),
+            binary_publishes: AtomicU64::new(0),
+            payload_errors: [const { AtomicU64::new(0) }; PAYLOAD_ERRORS.len()],
+            latency: Histogram::new(),
+        }
+    }
+
+    pub fn websocket_opened(&self) {
+        self.websocket_sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn websocket_closed(&self) {
+        self.websocket_sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn published(&self, path: PublishPath) {
+        match path {
+            PublishPath::Text => &self.text_publishes,
+            PublishPath::Binary => &self.binary_publishes,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn payload_rejected(&self, error: &ProtoBufPayloadError) {
+        let index = match error {
+            ProtoBufPayloadError::Overflow => 0,
+            ProtoBufPayloadError::ContentType => 1,
+            ProtoBufPayloadError::Serialize(_) => 2,
+            ProtoBufPayloadError::Deserialize(_) => 3,
+            ProtoBufPayloadError::Payload(_) => 4,
+        };
+
+        self.payload_errors[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A message reached the subscriber's mailbox `latency` after it was published
+    pub fn delivered(&self, latency: Duration) {
+        self.latency.observe(latency);
+    }
+
+    /// Prometheus text exposition format
+    pub fn render(&self, stats: &ServerStats) -> String {
+        let mut out = String::new();
+
+        gauge(&mut out, "push_websocket_sessions", "Open WebSocket sessions", self.websocket_sessions.load(Ordering::Relaxed));
+        gauge(&mut out, "push_clients", "Subscribers, WebSocket and long polling", stats.clients);
+        gauge(&mut out, "push_channels", "Channels with at least one subscriber", stats.channels);
+
+        header(&mut out, "push_publishes_total", "Publish requests from the backend by path", "counter");
+        writeln!(out, "push_publishes_total{{path=\"text\"}} {}", self.text_publishes.load(Ordering::Relaxed)).unwrap();
+        writeln!(out, "push_publishes_total{{path=\"binary\"}} {}", self.binary_publishes.load(Ordering::Relaxed)).unwrap();
+
+        counter(&mut out, "push_messages_published_total", "Batches published to channels", stats.messages.published);
+        counter(&mut out, "push_messages_delivered_total", "Batches put into subscriber mailboxes", stats.messages.delivered);
+        counter(&mut out, "push_messages_failed_total", "Batches not delivered because the subscriber was gone", stats.messages.failed);
+        counter(&mut out, "push_messages_dropped_total", "Batches thrown away by the slow consumer policy", stats.messages.dropped);
+        counter(&mut out, "push_slow_consumers_disconnected_total", "Subscribers disconnected by the slow consumer policy", stats.messages.disconnected);
+
+        header(&mut out, "push_payload_rejections_total", "Protobuf request bodies rejected by reason", "counter");
+        for (reason, count) in PAYLOAD_ERRORS.iter().zip(&self.payload_errors) {
+            writeln!(out, "push_payload_rejections_total{{reason=\"{reason}\"}} {}", count.load(Ordering::Relaxed)).unwrap();
+        }
+
+        header(&mut out, "push_delivery_latency_seconds", "Time from publish to the subscriber mailbox", "histogram");
+        self.latency.render(&mut out, "push_delivery_latency_seconds");
+
+        out
+    }
+}
+
+/// Fixed bucket histogram, buckets are kept not cumulative and summed on render
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len() + 1],
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        let index = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let mut count = 0;
+
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+
+            match LATENCY_BUCKETS.get(index) {
+                Some(bound) => writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}"),
+                None => writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}"),
+            }
+            .unwrap();
+        }
+
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+
+        writeln!(out, "{name}_sum {sum}").unwrap();
+        writeln!(out, "{name}_count {count}").unwrap();
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}").unwrap();
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    header(out, name, help, "gauge");
+    writeln!(out, "{name} {value}").unwrap();
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "counter");
+    writeln!(out, "{name} {value}").unwrap();
+}
+
+/// Routes of the separate `[metrics] listen` server
+pub fn routes_configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/metrics")
+        .wrap(from_fn(access::allowed_address))
+        .route(web::get().to(export)));
+}
+
+/// `GET /metrics`
+pub async fn export(router: web::Data<ShardRouter>) -> Result<HttpResponse, Error> {
+    let stats = processor::get_server_stats(&router).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render(&stats)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+
+        metrics.websocket_opened();
+        metrics.websocket_opened();
+        metrics.websocket_closed();
+        metrics.published(PublishPath::Binary);
+        metrics.payload_rejected(&ProtoBufPayloadError::ContentType);
+        metrics.delivered(Duration::from_micros(300));
+        metrics.delivered(Duration::from_millis(20));
+        metrics.delivered(Duration::from_secs(5));
+
+        let stats = ServerStats {
+            clients: 3,
+            channels: 2,
+            ..Default::default()
+        };
+        let text = metrics.render(&stats);
+
+        for line in [
+            "push_websocket_sessions 1",
+            "push_clients 3",
+            "push_channels 2",
+            "push_publishes_total{path=\"text\"} 0",
+            "push_publishes_total{path=\"binary\"} 1",
+            "push_payload_rejections_total{reason=\"content_type\"} 1",
+            "push_delivery_latency_seconds_bucket{le=\"0.0005\"} 1",
+            "push_delivery_latency_seconds_bucket{le=\"0.01\"} 1",
+            "push_delivery_latency_seconds_bucket{le=\"0.025\"} 2",
+            "push_delivery_latency_seconds_bucket{le=\"+Inf\"} 3",
+            "push_delivery_latency_seconds_sum 5.0203",
+            "push_delivery_latency_seconds_count 3",
+        ] {
+            assert!(text.lines().any(|rendered| rendered == line), "{line} is missing in\n{text}");
+        }
+    }
+}
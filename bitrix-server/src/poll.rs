@@ -0,0 +1,231 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::prelude::*;
+use actix_web_actors::ws::{CloseCode, CloseReason};
+use tokio::sync::oneshot;
+
+use bitrix_channels::Channel;
+
+use crate::{
+    logging,
+    message::{
+        ChannelMessage, Client, DisconnectMessage, ProtobufMessage, SessionInfo, SessionKind,
+    },
+    router::ShardRouter,
+    utils,
+};
+
+/// Messages published together reach the session within this after the first one
+const COLLECT_DELAY: Duration = Duration::from_millis(25);
+
+/// Long polling subscriber.
+///
+/// Lives until `COLLECT_DELAY` after the first batch for its channels or until
+/// `timeout` passes. Everything received goes back to the waiting HTTP handler
+/// through `responder`, nothing received drops it.
+pub struct PollSession {
+    router: ShardRouter,
+    info: Arc<SessionInfo>,
+    channels: Vec<Channel>,
+    timeout: Duration,
+    last_message_id: Option<Vec<u8>>,
+    received: Vec<ChannelMessage>,
+    responder: Option<oneshot::Sender<Vec<ChannelMessage>>>,
+}
+
+impl PollSession {
+    pub fn new(
+        router: ShardRouter,
+        channels: Vec<Channel>,
+        timeout: Duration,
+        last_message_id: Option<Vec<u8>>,
+        responder: oneshot::Sender<Vec<ChannelMessage>>,
+        remote_addr: Option<String>,
+    ) -> Self {
+        PollSession {
+            router,
+            info: Arc::new(SessionInfo::new(SessionKind::Polling, remote_addr)),
+            channels,
+            timeout,
+            last_message_id,
+            received: Vec::new(),
+            responder: Some(responder),
+        }
+    }
+
+    /// Drop expired messages and ones the client already got before reconnect
+    fn filter_delivered(&self, msg: ProtobufMessage) -> Option<ProtobufMessage> {
+        let now = utils::get_timestamp();
+
+        msg.without(|message| {
+            utils::is_expired(message, now) || self.last_message_id.as_ref() == Some(&message.id)
+        })
+    }
+}
+
+impl Actor for PollSession {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        log::debug!(session:% = self.info.id, channels:% = logging::channel_ids(&self.channels); "Started");
+
+        self.router.subscribe(
+            self.channels.clone(),
+            Client::new(ctx.address()).with_info(self.info.clone()),
+            self.last_message_id.clone(),
+        );
+
+        ctx.run_later(self.timeout, |act, ctx| {
+            log::trace!(session:% = act.info.id; "Timeout");
+            ctx.stop();
+        });
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        log::debug!(session:% = self.info.id; "Stopped");
+
+        if let Some(responder) = self.responder.take().filter(|_| !self.received.is_empty()) {
+            let messages = self
+                .received
+                .iter()
+                .map(|ChannelMessage(_, protobuf_msg)| utils::get_outgoing_messages(protobuf_msg.batch()).count())
+                .sum();
+
+            match responder.send(std::mem::take(&mut self.received)) {
+                Ok(()) => self.info.add_sent(messages),
+                Err(_) => log::debug!(session:% = self.info.id; "Client gone before delivery"),
+            }
+        }
+
+        self.router.unsubscribe(
+            self.channels.clone(),
+            Client::new(ctx.address()).with_info(self.info.clone()),
+        );
+    }
+}
+
+impl Handler<ChannelMessage> for PollSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: ChannelMessage, ctx: &mut Self::Context) {
+        let ChannelMessage(channel, protobuf_msg) = msg;
+
+        let protobuf_msg = match self.filter_delivered(protobuf_msg) {
+            Some(protobuf_msg) => protobuf_msg,
+            None => return,
+        };
+
+        if self.received.is_empty() {
+            ctx.run_later(COLLECT_DELAY, |_act, ctx| ctx.stop());
+        }
+
+        self.received.push(ChannelMessage(channel, protobuf_msg));
+    }
+}
+
+impl Handler<DisconnectMessage> for PollSession {
+    type Result = ();
+
+    fn handle(&mut self, _msg: DisconnectMessage, ctx: &mut Self::Context) {
+        ctx.stop();
+    }
+}
+
+/// Stops the session when the HTTP handler holding it is dropped, e.g. on client disconnect
+pub struct PollGuard(pub Addr<PollSession>);
+
+impl Drop for PollGuard {
+    fn drop(&mut self) {
+        self.0.do_send(DisconnectMessage(CloseReason::from(CloseCode::Away)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::items;
+
+    const CHANNEL: &str = "f0e5d42369441879d7e176c96cbbff2d";
+
+    fn channels() -> Vec<Channel> {
+        vec![Channel::create_private(CHANNEL.to_string())]
+    }
+
+    fn message(id: u8) -> ProtobufMessage {
+        ProtobufMessage::new(items::ResponseBatch {
+            responses: vec![items::Response {
+                command: Some(items::response::Command::OutgoingMessages(
+                    items::OutgoingMessagesResponse {
+                        messages: vec![items::OutgoingMessage {
+                            id: vec![id],
+                            created: utils::get_timestamp(),
+                            ..Default::default()
+                        }],
+                    },
+                )),
+            }],
+        })
+    }
+
+    fn ids(received: &[ChannelMessage]) -> Vec<u8> {
+        received
+            .iter()
+            .flat_map(|ChannelMessage(_, protobuf_msg)| utils::get_outgoing_messages(protobuf_msg.batch()))
+            .map(|message| message.id[0])
+            .collect()
+    }
+
+    fn start(
+        router: &ShardRouter,
+        timeout: Duration,
+        last_message_id: Option<Vec<u8>>,
+    ) -> (Addr<PollSession>, oneshot::Receiver<Vec<ChannelMessage>>) {
+        let (responder, waiter) = oneshot::channel();
+        let session = PollSession::new(router.clone(), channels(), timeout, last_message_id, responder, None).start();
+
+        (session, waiter)
+    }
+
+    #[actix_web::test]
+    async fn test_collects_messages_published_together() {
+        let router = ShardRouter::default();
+        let (_session, waiter) = start(&router, Duration::from_secs(5), None);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        router.publish(channels(), message(1));
+        router.publish(channels(), message(2));
+
+        assert_eq!(ids(&waiter.await.unwrap()), vec![1, 2]);
+    }
+
+    #[actix_web::test]
+    async fn test_timeout_drops_responder() {
+        let (session, waiter) = start(&ShardRouter::default(), Duration::from_millis(50), None);
+
+        assert!(waiter.await.is_err());
+        assert!(!session.connected());
+    }
+
+    #[actix_web::test]
+    async fn test_replay_since_mid() {
+        let router = ShardRouter::default();
+        router.publish(channels(), message(1));
+        router.publish(channels(), message(2));
+        router.publish(channels(), message(3));
+
+        let (_session, waiter) = start(&router, Duration::from_secs(5), Some(vec![1]));
+
+        assert_eq!(ids(&waiter.await.unwrap()), vec![2, 3]);
+    }
+
+    #[actix_web::test]
+    async fn test_guard_stops_session() {
+        let (session, waiter) = start(&ShardRouter::default(), Duration::from_secs(5), None);
+
+        drop(PollGuard(session.clone()));
+
+        assert!(waiter.await.is_err());
+        assert!(!session.connected());
+    }
+}
@@ -0,0 +1,205 @@
+use actix_web::Error;
+
+use bitrix_channels::{Channel, ChannelParseResult, ChannelType, Parser};
+
+use crate::{
+    items,
+    logging::{self, Redacted},
+    message::ProtobufMessage,
+    router::ShardRouter,
+    stats::ServerStats,
+    utils,
+};
+
+/// Who sent a `RequestBatch`
+#[derive(Debug, Clone)]
+pub enum Origin {
+    /// Bitrix backend, `POST /pub/?binaryMode=true`. Trusted request.
+    Backend,
+    /// Browser client, identified by its private channel. Untrusted request.
+    Client(Option<Channel>),
+}
+
+impl Origin {
+    fn is_trusted(&self) -> bool {
+        matches!(self, Origin::Backend)
+    }
+
+    /// Clients may only reach channels the backend signed for them, whatever `[security] enabled` says
+    fn parse_channel(
+        &self,
+        parser: &Parser,
+        id: Vec<u8>,
+        is_private: bool,
+        signature: &[u8],
+    ) -> ChannelParseResult<Channel> {
+        match self {
+            Origin::Backend => parser.parse_bytes(id, is_private, signature),
+            Origin::Client(_) => parser.parse_signed_bytes(id, is_private, signature),
+        }
+    }
+
+    fn get_sender(&self) -> items::Sender {
+        match self {
+            Origin::Backend => items::Sender {
+                r#type: items::SenderType::Backend as i32,
+                id: vec![],
+            },
+            Origin::Client(private_channel) => items::Sender {
+                r#type: items::SenderType::Client as i32,
+                id: private_channel
+                    .as_ref()
+                    .and_then(|channel| Vec::<u8>::try_from(channel).ok())
+                    .unwrap_or_default(),
+            },
+        }
+    }
+}
+
+/// Process every command of a request batch, returns responses to send back
+pub async fn process_requests(
+    requests: Vec<items::Request>,
+    origin: &Origin,
+    parser: &Parser,
+    router: &ShardRouter,
+) -> Result<Vec<items::Response>, Error> {
+    let mut responses = Vec::new();
+
+    for request in requests {
+        if request.command.is_none() {
+            log::warn!("Receive empty command");
+            continue;
+        }
+
+        let request_command = request.command.unwrap();
+
+        match request_command {
+            items::request::Command::IncomingMessages(incoming_message_request) => {
+                log::debug!(request:% = Redacted(&incoming_message_request); "Process income messages request");
+
+                for income_message in incoming_message_request.messages.into_iter() {
+                    publish_incoming_message(income_message, origin, parser, router);
+                }
+            }
+            items::request::Command::ChannelStats(channel_stats_request) => {
+                log::debug!("Process channel stats request: {channel_stats_request:?}");
+
+                let channels = channel_stats_request
+                    .channels
+                    .into_iter()
+                    .filter_map(|channel_id| {
+                        origin
+                            .parse_channel(parser, channel_id.id, channel_id.is_private, &channel_id.signature)
+                            .map_err(|error| log::warn!("Skip channel from {origin:?}: {error}"))
+                            .ok()
+                    })
+                    .collect();
+
+                responses.push(items::Response {
+                    command: Some(items::response::Command::ChannelStats(
+                        get_channel_stats(router, channels).await?,
+                    )),
+                });
+            }
+            items::request::Command::ServerStats(server_stats_request) if origin.is_trusted() => {
+                log::debug!("Process server stats request: {server_stats_request:?}");
+
+                let json = serde_json::to_string(&get_server_stats(router).await?)?;
+
+                responses.push(items::Response {
+                    command: Some(items::response::Command::ServerStats(
+                        items::JsonResponse { json },
+                    )),
+                });
+            }
+            _ => {
+                log::error!("Got strange command from {origin:?}: {:#?}. Ignore.", request_command);
+                continue;
+            }
+        }
+    }
+
+    Ok(responses)
+}
+
+fn publish_incoming_message(
+    income_message: items::IncomingMessage,
+    origin: &Origin,
+    parser: &Parser,
+    router: &ShardRouter,
+) {
+    let mut channel_ids = Vec::new();
+
+    for receiver in income_message.receivers {
+        match origin.parse_channel(parser, receiver.id, receiver.is_private, &receiver.signature) {
+            Ok(channel) => {
+                channel_ids.push(channel);
+            }
+            Err(error) => {
+                log::warn!("Skip receiver from {origin:?}: {error}");
+                continue;
+            }
+        }
+    }
+
+    if channel_ids.is_empty() {
+        return;
+    }
+
+    let message_id = utils::get_message_id();
+
+    log::debug!(
+        channels:% = logging::channel_ids(&channel_ids),
+        message_id:% = utils::encode_message_id(&message_id),
+        sender:? = origin,
+        body:% = Redacted(&income_message.body);
+        "Publish income message"
+    );
+
+    let protobuf_message = items::ResponseBatch {
+        responses: vec![items::Response {
+            command: Some(items::response::Command::OutgoingMessages(
+                items::OutgoingMessagesResponse {
+                    messages: vec![items::OutgoingMessage {
+                        id: message_id,
+                        body: income_message.body,
+                        expiry: income_message.expiry,
+                        created: utils::get_timestamp(),
+                        sender: Some(origin.get_sender()),
+                    }],
+                },
+            )),
+        }],
+    };
+
+    router.publish(channel_ids, ProtobufMessage::new(protobuf_message));
+}
+
+/// Ask the shards whether anybody listens to the channels
+pub async fn get_channel_stats(
+    router: &ShardRouter,
+    channels: Vec<Channel>,
+) -> Result<items::ChannelStatsResponse, Error> {
+    let online = router.channel_stats(channels.clone()).await;
+
+    Ok(items::ChannelStatsResponse {
+        channels: channels
+            .iter()
+            .zip(online)
+            .filter_map(|(channel, is_online)| {
+                Some(items::ChannelStats {
+                    id: Vec::<u8>::try_from(channel).ok()?,
+                    is_private: channel.get_kind() == ChannelType::Private,
+                    is_online,
+                })
+            })
+            .collect(),
+    })
+}
+
+pub async fn get_server_stats(router: &ShardRouter) -> Result<ServerStats, Error> {
+    router
+        .server_stats()
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)
+}
@@ -0,0 +1,165 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use actix_web::http::header::HttpDate;
+use prost::Message;
+use serde_json::{json, Value};
+
+use bitrix_channels::Channel;
+
+use crate::{items, message::ProtobufMessage, utils};
+
+const MESSAGE_START: &str = "#!NGINXNMS!#";
+const MESSAGE_END: &str = "#!NGINXNME!#";
+
+/// Wire format a subscriber understands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Protobuf `ResponseBatch` frames, `binaryMode=true`
+    Binary,
+    /// JSON messages in nginx push stream envelopes.
+    /// Clients without `revision` are the nginx era ones and don't know about `mid`.
+    Text { with_mid: bool },
+}
+
+impl Protocol {
+    pub fn negotiate(is_binary: Option<bool>, revision: Option<i32>) -> Self {
+        match is_binary {
+            Some(true) => Protocol::Binary,
+            _ => Protocol::Text {
+                with_mid: revision.is_some(),
+            },
+        }
+    }
+}
+
+pub fn encode_binary(batch: &items::ResponseBatch) -> Result<Vec<u8>, prost::EncodeError> {
+    let mut body = Vec::new();
+    batch.encode(&mut body)?;
+    Ok(body)
+}
+
+/// Envelope every outgoing message of the batch.
+///
+/// `id` and `tag` are the history sequence of the message, the same for every
+/// subscriber and connection, so a client can tell what it missed.
+pub fn encode_text(channel: &Channel, msg: &ProtobufMessage, with_mid: bool) -> String {
+    msg.sequenced()
+        .map(|(sequence, message)| encode_text_message(channel, message, with_mid, sequence))
+        .collect()
+}
+
+fn encode_text_message(
+    channel: &Channel,
+    message: &items::OutgoingMessage,
+    with_mid: bool,
+    sequence: u64,
+) -> String {
+    /* Body is a JSON document from Bitrix, anything else goes as a plain string */
+    let text = serde_json::from_str::<Value>(&message.body)
+        .unwrap_or_else(|_| Value::String(message.body.clone()));
+
+    let mut fields = json!({
+        "id": sequence,
+        "channel": channel.to_string(),
+        "tag": sequence.to_string(),
+        "time": get_http_time(message.created),
+        "text": text,
+    });
+
+    if with_mid {
+        fields["mid"] = Value::String(utils::encode_message_id(&message.id));
+    }
+
+    format!("{MESSAGE_START}{fields}{MESSAGE_END}")
+}
+
+/// `created` in `Last-Modified` format, the `time` field of the text protocol
+pub fn get_http_time(created: u32) -> String {
+    HttpDate::from(UNIX_EPOCH + Duration::from_secs(created as u64)).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(bodies: &[&str], sequence: u64) -> ProtobufMessage {
+        ProtobufMessage::new(items::ResponseBatch {
+            responses: vec![items::Response {
+                command: Some(items::response::Command::OutgoingMessages(
+                    items::OutgoingMessagesResponse {
+                        messages: bodies
+                            .iter()
+                            .map(|body| items::OutgoingMessage {
+                                id: vec![0, 255],
+                                body: body.to_string(),
+                                ..Default::default()
+                            })
+                            .collect(),
+                    },
+                )),
+            }],
+        })
+        .with_sequence(sequence)
+    }
+
+    fn decode(envelope: &str) -> Value {
+        let json = envelope
+            .strip_prefix(MESSAGE_START)
+            .and_then(|envelope| envelope.strip_suffix(MESSAGE_END))
+            .unwrap();
+
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_negotiate_protocol() {
+        assert_eq!(Protocol::negotiate(Some(true), Some(19)), Protocol::Binary);
+        assert_eq!(
+            Protocol::negotiate(Some(false), Some(19)),
+            Protocol::Text { with_mid: true }
+        );
+        assert_eq!(
+            Protocol::negotiate(None, None),
+            Protocol::Text { with_mid: false }
+        );
+    }
+
+    #[test]
+    fn test_encode_text_envelope() {
+        let channel = Channel::create_private("abc".to_string());
+
+        let encoded = encode_text(&channel, &batch(&[r#"{"module_id":"im"}"#], 1), true);
+        let fields = decode(&encoded);
+
+        assert_eq!(fields["id"], 1);
+        assert_eq!(fields["mid"], "00ff");
+        assert_eq!(fields["channel"], "abc");
+        assert_eq!(fields["tag"], "1");
+        assert_eq!(fields["time"], "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(fields["text"]["module_id"], "im");
+    }
+
+    #[test]
+    fn test_encode_text_without_mid() {
+        let channel = Channel::create_private("abc".to_string());
+
+        let fields = decode(&encode_text(&channel, &batch(&["plain"], 1), false));
+
+        assert!(fields.get("mid").is_none());
+        assert_eq!(fields["text"], "plain");
+    }
+
+    #[test]
+    fn test_encode_text_batch() {
+        let channel = Channel::create_private("abc".to_string());
+
+        let encoded = encode_text(&channel, &batch(&["1", "2"], 6), true);
+        let ids = encoded
+            .split_inclusive(MESSAGE_END)
+            .map(|envelope| decode(envelope)["id"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(ids, vec![6, 7]);
+        assert_eq!(encoded.matches(MESSAGE_START).count(), 2);
+    }
+}
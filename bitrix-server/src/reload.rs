@@ -0,0 +1,186 @@
+use std::io;
+use std::sync::{Arc, RwLock};
+
+use actix_web::web;
+use bitrix_channels::Parser;
+
+use crate::{
+    access::AccessPolicy,
+    logging::{self, Logger},
+    settings::Settings,
+};
+
+/// Value replaced in place on reload, readers take the current one
+#[derive(Debug, Default)]
+pub struct Swap<T>(RwLock<Arc<T>>);
+
+impl<T> Swap<T> {
+    pub fn new(value: T) -> Self {
+        Swap(RwLock::new(Arc::new(value)))
+    }
+
+    pub fn load(&self) -> Arc<T> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn store(&self, value: T) {
+        *self.0.write().unwrap() = Arc::new(value);
+    }
+}
+
+/// Everything the running server picks up from a new `push_config.toml`
+pub struct Reloader {
+    pub settings: web::Data<Swap<Settings>>,
+    pub parser: web::Data<Swap<Parser>>,
+    pub access: web::Data<Swap<AccessPolicy>>,
+    pub logger: &'static Logger,
+}
+
+impl Reloader {
+    /// Read the config again and swap what can change, nothing changes if it is broken
+    pub fn reload(&self) -> Result<(), String> {
+        let settings = Settings::new().map_err(|error| error.to_string())?;
+        let access = AccessPolicy::new(&settings.access, &settings.security)?;
+
+        let current = self.settings.load();
+
+        for name in restart_only_changes(&current, &settings) {
+            log::warn!("{name} changed in config, it takes effect after a restart");
+        }
+
+        if current.log.level != settings.log.level && logging::level_from_env() {
+            log::warn!("log.level changed in config, RUST_LOG takes precedence over it");
+        }
+
+        let settings = keep_restart_only(&current, settings);
+
+        self.logger.set(&settings.log);
+        self.parser.store(settings.security.parser());
+        self.access.store(access);
+        self.settings.store(settings);
+
+        Ok(())
+    }
+}
+
+/// Settings read once at startup
+fn restart_only_changes(current: &Settings, new: &Settings) -> Vec<&'static str> {
+    [
+        ("general.port", current.general.port != new.general.port),
+        ("general.workers", current.general.workers != new.general.workers),
+        ("general.shards", current.general.shards != new.general.shards),
+        ("general.shutdown_timeout", current.general.shutdown_timeout != new.general.shutdown_timeout),
+        ("history.size", current.history.size != new.history.size),
+        ("history.channels", current.history.channels != new.history.channels),
+        ("backpressure", current.backpressure != new.backpressure),
+        ("cluster", current.cluster != new.cluster),
+        ("tls", current.tls != new.tls),
+        ("metrics", current.metrics != new.metrics),
+        ("inspector", current.inspector != new.inspector),
+    ]
+    .into_iter()
+    .filter(|(_, changed)| *changed)
+    .map(|(name, _)| name)
+    .collect()
+}
+
+/// `new` with the settings read at startup put back, so they always show what runs
+fn keep_restart_only(current: &Settings, new: Settings) -> Settings {
+    Settings {
+        general: current.general.clone(),
+        history: current.history.clone(),
+        backpressure: current.backpressure.clone(),
+        cluster: current.cluster.clone(),
+        tls: current.tls.clone(),
+        metrics: current.metrics.clone(),
+        inspector: current.inspector.clone(),
+        ..new
+    }
+}
+
+/// Reload the config every time the process gets SIGHUP
+#[cfg(unix)]
+pub fn reload_on_sighup(reloader: Reloader) -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    actix::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match reloader.reload() {
+                Ok(()) => log::info!("config reloaded"),
+                Err(error) => log::error!("config reload failed, keep the old one: {error}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn reload_on_sighup(_reloader: Reloader) -> io::Result<()> {
+    log::warn!("config reload on SIGHUP is not supported on this platform");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Settings {
+        config::Config::builder()
+            .add_source(config::File::from_str(
+                "[general]\nport = 9099\nworkers = 2\n[security]\nenabled = false\nkey = \"\"\n[log]\nlevel = \"info\"",
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_swap() {
+        let swap = Swap::new(1);
+        let before = swap.load();
+
+        swap.store(2);
+
+        assert_eq!(*before, 1);
+        assert_eq!(*swap.load(), 2);
+    }
+
+    #[test]
+    fn test_restart_only_changes() {
+        let current = settings();
+        let mut new = settings();
+
+        new.security.enabled = true;
+        new.log.level = "debug".to_string();
+        new.polling.timeout = 10;
+
+        assert!(restart_only_changes(&current, &new).is_empty());
+
+        new.general.port = 9100;
+        new.backpressure.buffer_size = 1;
+
+        assert_eq!(restart_only_changes(&current, &new), vec!["general.port", "backpressure"]);
+    }
+
+    #[test]
+    fn test_restart_only_changes_are_not_applied() {
+        let startup = settings();
+        let mut new = settings();
+
+        new.general.port = 9100;
+        new.polling.timeout = 10;
+
+        let applied = keep_restart_only(&startup, new.clone());
+
+        assert_eq!(applied.general.port, 9099);
+        assert_eq!(applied.polling.timeout, 10);
+
+        /* The next reload still warns about the port */
+        assert_eq!(restart_only_changes(&applied, &new), vec!["general.port"]);
+    }
+}
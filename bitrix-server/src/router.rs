@@ -0,0 +1,618 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use actix::prelude::*;
+use bitrix_channels::Channel;
+
+use crate::{
+    backend::{InProcessBackend, PubSubBackend},
+    history::History,
+    inspector::TapEvent,
+    logging,
+    message::{
+        ChannelStatsMessage, Client, HistorySequenceMessage, ProtobufMessage, RegisterSessionMessage,
+        SendPullMessage, ServerStatsMessage, ShardStatsMessage, SubscribeChannelMessage,
+        TapMessage, UnregisterSessionMessage, UnsubscribeChannelMessage,
+    },
+    server::WsPullServer,
+    settings::{self, Backpressure},
+    shard::WsPullShard,
+    stats::ServerStats,
+    utils,
+};
+
+#[derive(Default)]
+struct Counters {
+    published: AtomicU64,
+    /// History sequence of the last published message
+    sequence: AtomicU64,
+}
+
+/// Entry point of publishes and subscriptions, cheap to clone.
+///
+/// Channels live in `WsPullShard`s, each on its own arbiter, picked by the
+/// hash of the channel id. Sessions and publishers send to the shards right
+/// away, no actor stands between them. `WsPullServer` only keeps the list of
+/// sessions for the admin API and shutdown.
+#[derive(Clone)]
+pub struct ShardRouter {
+    shards: Arc<[Addr<WsPullShard>]>,
+    sessions: Addr<WsPullServer>,
+    backend: Arc<dyn PubSubBackend>,
+    counters: Arc<Counters>,
+    /// Inspector pages watching every delivered publish
+    taps: Arc<RwLock<Vec<Recipient<TapMessage>>>>,
+}
+
+impl Default for ShardRouter {
+    fn default() -> Self {
+        ShardRouter::new(
+            WsPullServer::from_registry(),
+            &settings::History::default(),
+            Box::new(InProcessBackend),
+            settings::General::default_shards(),
+            Backpressure::default(),
+        )
+    }
+}
+
+impl ShardRouter {
+    pub fn new(
+        sessions: Addr<WsPullServer>,
+        history: &settings::History,
+        mut backend: Box<dyn PubSubBackend>,
+        shards: usize,
+        backpressure: Backpressure,
+    ) -> Self {
+        let shards = shards.max(1);
+        let history_channels = history.channels.div_ceil(shards);
+
+        let shards = (0..shards)
+            .map(|_| {
+                let backpressure = backpressure.clone();
+                let history = History::new(history.size, history_channels);
+                WsPullShard::start_in_arbiter(&Arbiter::new().handle(), |_| {
+                    WsPullShard::new(backpressure, history)
+                })
+            })
+            .collect();
+
+        let local = ShardRouter {
+            shards,
+            sessions,
+            backend: Arc::new(InProcessBackend),
+            counters: Arc::default(),
+            taps: Arc::default(),
+        };
+
+        /* Publishes of other instances reach local subscribers only */
+        backend.start(local.clone());
+
+        ShardRouter {
+            backend: Arc::from(backend),
+            ..local
+        }
+    }
+
+    fn get_shard_index(&self, channel_name: &Channel) -> usize {
+        let mut hasher = DefaultHasher::new();
+        channel_name.to_string().hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    /// Channels grouped by shard, positions are kept to restore the original order
+    fn split_by_shard(&self, channel_names: Vec<Channel>) -> Vec<Vec<(usize, Channel)>> {
+        let mut groups = vec![Vec::new(); self.shards.len()];
+
+        for (position, channel_name) in channel_names.into_iter().enumerate() {
+            groups[self.get_shard_index(&channel_name)].push((position, channel_name));
+        }
+
+        groups
+    }
+
+    /// Shards with their part of the channels, shards without channels are skipped
+    fn by_shard(&self, channel_names: Vec<Channel>) -> impl Iterator<Item = (&Addr<WsPullShard>, Vec<Channel>)> {
+        self.shards
+            .iter()
+            .zip(self.split_by_shard(channel_names))
+            .filter(|(_, group)| !group.is_empty())
+            .map(|(shard, group)| (shard, group.into_iter().map(|(_, channel_name)| channel_name).collect()))
+    }
+
+    /// Subscribe the client and replay what it missed after `last_message_id`.
+    ///
+    /// The sequence of `last_message_id` is looked up first, then every shard
+    /// subscribes the client and replays its own channels in one go, so the
+    /// client gets the history of a shard before its live messages.
+    pub fn subscribe(&self, channels: Vec<Channel>, client: Client, last_message_id: Option<Vec<u8>>) {
+        self.sessions.do_send(RegisterSessionMessage(client.clone(), channels.clone()));
+
+        let Some(last_message_id) = last_message_id else {
+            self.subscribe_after(channels, client, None);
+            return;
+        };
+
+        let requests = self
+            .by_shard(channels.clone())
+            .map(|(shard, channels)| shard.send(HistorySequenceMessage(channels, last_message_id.clone())))
+            .collect::<Vec<_>>();
+
+        let router = self.clone();
+
+        actix::spawn(async move {
+            let mut last_sequence = None;
+
+            for request in requests {
+                match request.await {
+                    Ok(sequence) => last_sequence = last_sequence.max(sequence),
+                    Err(error) => log::error!("ShardRouter::subscribe => {error}"),
+                }
+            }
+
+            if last_sequence.is_none() {
+                log::debug!("ShardRouter::subscribe => message id not found in history");
+            }
+
+            router.subscribe_after(channels, client, last_sequence);
+        });
+    }
+
+    fn subscribe_after(&self, channels: Vec<Channel>, client: Client, last_sequence: Option<u64>) {
+        for (shard, channels) in self.by_shard(channels) {
+            shard.do_send(SubscribeChannelMessage(channels, client.clone(), last_sequence));
+        }
+    }
+
+    pub fn unsubscribe(&self, channels: Vec<Channel>, client: Client) {
+        for (shard, channels) in self.by_shard(channels) {
+            shard.do_send(UnsubscribeChannelMessage(channels, client.clone()));
+        }
+
+        self.sessions.do_send(UnregisterSessionMessage(client));
+    }
+
+    /// Deliver to local subscribers and to the other instances of the cluster
+    pub fn publish(&self, channel_names: Vec<Channel>, protobuf_msg: ProtobufMessage) {
+        if let Some(protobuf_msg) = self.deliver(channel_names.clone(), protobuf_msg) {
+            self.backend.publish(&channel_names, &protobuf_msg);
+        }
+    }
+
+    /// Deliver a publish to subscribers of this instance, `None` if it has already expired
+    pub fn deliver(&self, channel_names: Vec<Channel>, protobuf_msg: ProtobufMessage) -> Option<ProtobufMessage> {
+        let protobuf_msg = match protobuf_msg.without_expired(utils::get_timestamp()) {
+            Some(protobuf_msg) => protobuf_msg,
+            None => {
+                log::debug!("ShardRouter::deliver => message expired before delivery");
+                return None;
+            }
+        };
+
+        log::debug!(
+            channels:% = logging::channel_ids(&channel_names),
+            message_ids:% = logging::message_ids(protobuf_msg.batch());
+            "ShardRouter::deliver"
+        );
+
+        let messages = utils::get_outgoing_messages(protobuf_msg.batch()).count() as u64;
+        let sequence = self.counters.sequence.fetch_add(messages, Ordering::Relaxed) + 1;
+        self.counters.published.fetch_add(1, Ordering::Relaxed);
+
+        let protobuf_msg = protobuf_msg.with_sequence(sequence);
+
+        self.send_tap(&channel_names, &protobuf_msg);
+
+        for (shard, channel_names) in self.by_shard(channel_names) {
+            shard.do_send(SendPullMessage(channel_names, protobuf_msg.clone()));
+        }
+
+        Some(protobuf_msg)
+    }
+
+    /// Online status for every channel, in the same order
+    pub async fn channel_stats(&self, channel_names: Vec<Channel>) -> Vec<bool> {
+        let mut online = vec![false; channel_names.len()];
+
+        let requests = self
+            .shards
+            .iter()
+            .zip(self.split_by_shard(channel_names))
+            .filter(|(_, group)| !group.is_empty())
+            .map(|(shard, group)| {
+                let (positions, channel_names): (Vec<_>, Vec<_>) = group.into_iter().unzip();
+                (positions, shard.send(ChannelStatsMessage(channel_names)))
+            })
+            .collect::<Vec<_>>();
+
+        for (positions, request) in requests {
+            match request.await {
+                Ok(shard_online) => {
+                    for (position, is_online) in positions.into_iter().zip(shard_online) {
+                        online[position] = is_online;
+                    }
+                }
+                Err(error) => log::error!("ShardRouter::channel_stats => {error}"),
+            }
+        }
+
+        online
+    }
+
+    pub async fn server_stats(&self) -> Result<ServerStats, MailboxError> {
+        let mut stats = self.sessions.send(ServerStatsMessage).await?;
+
+        stats.messages.published = self.counters.published.load(Ordering::Relaxed);
+
+        let requests = self
+            .shards
+            .iter()
+            .map(|shard| shard.send(ShardStatsMessage))
+            .collect::<Vec<_>>();
+
+        for request in requests {
+            match request.await {
+                Ok(shard_stats) => {
+                    stats.channels += shard_stats.channels;
+                    stats.subscribers += shard_stats.subscribers;
+                    stats.messages.delivered += shard_stats.delivered;
+                    stats.messages.failed += shard_stats.failed;
+                    stats.messages.dropped += shard_stats.dropped;
+                    stats.messages.disconnected += shard_stats.disconnected;
+                }
+                Err(error) => log::error!("ShardRouter::server_stats => {error}"),
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Send every delivered publish to the inspector page
+    pub fn tap(&self, recipient: Recipient<TapMessage>) {
+        self.taps.write().unwrap().push(recipient);
+    }
+
+    /// One event per publish with all its channels, serialized once for all
+    /// inspector pages. A page that is behind misses events.
+    fn send_tap(&self, channel_names: &[Channel], msg: &ProtobufMessage) {
+        let taps = self.taps.read().unwrap();
+
+        if taps.is_empty() {
+            return;
+        }
+
+        let event = match serde_json::to_string(&TapEvent::new(channel_names, msg.batch())) {
+            Ok(event) => event,
+            Err(error) => {
+                log::error!("ShardRouter::send_tap => {error}");
+                return;
+            }
+        };
+
+        let mut closed = false;
+
+        for tap in taps.iter() {
+            match tap.try_send(TapMessage(event.clone())) {
+                Ok(()) => {}
+                Err(SendError::Closed(_)) => closed = true,
+                Err(error) => log::debug!("ShardRouter::send_tap => {error}"),
+            }
+        }
+
+        drop(taps);
+
+        if closed {
+            self.taps.write().unwrap().retain(|tap| tap.connected());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web_actors::ws::{CloseCode, CloseReason};
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        items,
+        message::{ChannelMessage, DisconnectMessage},
+    };
+
+    struct Subscriber(mpsc::UnboundedSender<ChannelMessage>);
+
+    impl Actor for Subscriber {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<ChannelMessage> for Subscriber {
+        type Result = ();
+
+        fn handle(&mut self, msg: ChannelMessage, _ctx: &mut Self::Context) {
+            self.0.send(msg).unwrap();
+        }
+    }
+
+    impl Handler<DisconnectMessage> for Subscriber {
+        type Result = ();
+
+        fn handle(&mut self, _msg: DisconnectMessage, ctx: &mut Self::Context) {
+            ctx.stop();
+        }
+    }
+
+    struct Tap(mpsc::UnboundedSender<String>);
+
+    impl Actor for Tap {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<TapMessage> for Tap {
+        type Result = ();
+
+        fn handle(&mut self, msg: TapMessage, _ctx: &mut Self::Context) {
+            self.0.send(msg.0).unwrap();
+        }
+    }
+
+    fn router(shards: usize) -> ShardRouter {
+        ShardRouter::new(
+            WsPullServer::default().start(),
+            &settings::History { size: 0, ..Default::default() },
+            Box::new(InProcessBackend),
+            shards,
+            Backpressure::default(),
+        )
+    }
+
+    fn subscriber() -> (Client, mpsc::UnboundedReceiver<ChannelMessage>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        (Client::new(Subscriber(sender).start()), receiver)
+    }
+
+    fn channels(count: usize) -> Vec<Channel> {
+        (0..count)
+            .map(|number| Channel::create_private(format!("{number:032x}")))
+            .collect()
+    }
+
+    fn message() -> ProtobufMessage {
+        ProtobufMessage::new(items::ResponseBatch {
+            responses: vec![items::Response {
+                command: Some(items::response::Command::OutgoingMessages(
+                    items::OutgoingMessagesResponse {
+                        messages: vec![items::OutgoingMessage::default()],
+                    },
+                )),
+            }],
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_publish_reaches_every_shard() {
+        let router = router(4);
+        let channels = channels(16);
+        let (client, mut receiver) = subscriber();
+
+        router.subscribe(channels.clone(), client, None);
+        router.publish(channels.clone(), message());
+
+        let mut received = Vec::new();
+        for _ in 0..channels.len() {
+            let ChannelMessage(channel_name, _) = receiver.recv().await.unwrap();
+            received.push(channel_name.to_string());
+        }
+        received.sort();
+
+        assert_eq!(received, channels.iter().map(Channel::to_string).collect::<Vec<_>>());
+
+        let stats = router.server_stats().await.unwrap();
+
+        assert_eq!(stats.clients, 1);
+        assert_eq!(stats.channels, 16);
+        assert_eq!(stats.messages.published, 1);
+        assert_eq!(stats.messages.delivered, 16);
+    }
+
+    #[actix_web::test]
+    async fn test_replay_merges_shards() {
+        let router = ShardRouter::new(
+            WsPullServer::default().start(),
+            &settings::History::default(),
+            Box::new(InProcessBackend),
+            4,
+            Backpressure::default(),
+        );
+        let channels = channels(8);
+
+        for (number, channel) in channels.iter().enumerate() {
+            let msg = ProtobufMessage::new(items::ResponseBatch {
+                responses: vec![items::Response {
+                    command: Some(items::response::Command::OutgoingMessages(
+                        items::OutgoingMessagesResponse {
+                            messages: vec![items::OutgoingMessage {
+                                id: vec![number as u8],
+                                ..Default::default()
+                            }],
+                        },
+                    )),
+                }],
+            });
+
+            router.publish(vec![channel.clone()], msg);
+        }
+
+        let (client, mut receiver) = subscriber();
+        router.subscribe(channels.clone(), client, Some(vec![2]));
+
+        let mut sequences = Vec::new();
+        while let Ok(Some(ChannelMessage(_, replayed))) =
+            tokio::time::timeout(Duration::from_millis(50), receiver.recv()).await
+        {
+            sequences.extend(replayed.sequenced().map(|(sequence, message)| (sequence, message.id[0])));
+        }
+        sequences.sort();
+
+        assert_eq!(sequences, vec![(4, 3), (5, 4), (6, 5), (7, 6), (8, 7)]);
+    }
+
+    #[actix_web::test]
+    async fn test_replay_comes_before_live_messages() {
+        let router = ShardRouter::new(
+            WsPullServer::default().start(),
+            &settings::History::default(),
+            Box::new(InProcessBackend),
+            1,
+            Backpressure::default(),
+        );
+        let channels = channels(1);
+
+        let publish = |id: u8| {
+            router.publish(
+                channels.clone(),
+                ProtobufMessage::new(items::ResponseBatch {
+                    responses: vec![items::Response {
+                        command: Some(items::response::Command::OutgoingMessages(
+                            items::OutgoingMessagesResponse {
+                                messages: vec![items::OutgoingMessage {
+                                    id: vec![id],
+                                    ..Default::default()
+                                }],
+                            },
+                        )),
+                    }],
+                }),
+            )
+        };
+
+        for id in 1..=3 {
+            publish(id);
+        }
+
+        let (client, mut receiver) = subscriber();
+        router.subscribe(channels.clone(), client, Some(vec![1]));
+
+        /* Published while the router looks up the last message, replayed along with the rest */
+        publish(4);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        publish(5);
+
+        let mut batches = Vec::new();
+        for _ in 0..2 {
+            let ChannelMessage(_, msg) = receiver.recv().await.unwrap();
+            batches.push(utils::get_outgoing_messages(msg.batch()).map(|message| message.id[0]).collect::<Vec<_>>());
+        }
+
+        assert_eq!(batches, vec![vec![2, 3, 4], vec![5]]);
+    }
+
+    #[actix_web::test]
+    async fn test_server_stats_counters() {
+        let router = router(2);
+        let channels = channels(2);
+
+        let mut clients = Vec::new();
+        for subscribed in [&channels[..], &channels[..1]] {
+            let (client, receiver) = subscriber();
+            router.subscribe(subscribed.to_vec(), client.clone(), None);
+            clients.push((client, receiver));
+        }
+
+        router.publish(channels.clone(), message());
+
+        let stats = router.server_stats().await.unwrap();
+
+        assert_eq!(stats.clients, 2);
+        assert_eq!(stats.channels, 2);
+        assert_eq!(stats.subscribers, 3);
+        assert_eq!(stats.messages.published, 1);
+        assert_eq!(stats.messages.delivered, 3);
+        assert_eq!(stats.messages.failed, 0);
+
+        /* The second subscriber is gone before the next publish reaches it */
+        clients[1].0.disconnect(CloseReason::from(CloseCode::Away));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        router.publish(channels[..1].to_vec(), message());
+
+        let stats = router.server_stats().await.unwrap();
+
+        assert_eq!(stats.clients, 1);
+        assert_eq!(stats.subscribers, 2);
+        assert_eq!(stats.messages.published, 2);
+        assert_eq!(stats.messages.delivered, 4);
+        assert_eq!(stats.messages.failed, 1);
+
+        let json = serde_json::to_value(&stats).unwrap();
+
+        assert_eq!(json["subscribers"], 2);
+        assert!(!json.to_string().contains(&channels[0].to_string()));
+    }
+
+    #[actix_web::test]
+    async fn test_tap_gets_one_event_per_publish() {
+        let router = router(4);
+        let channels = channels(8);
+        let (sender, mut events) = mpsc::unbounded_channel();
+
+        router.tap(Tap(sender).start().recipient());
+        router.publish(channels.clone(), message());
+
+        let event: serde_json::Value = serde_json::from_str(&events.recv().await.unwrap()).unwrap();
+
+        assert_eq!(event["channels"].as_array().unwrap().len(), channels.len());
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        assert!(events.try_recv().is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_channel_stats_keep_order() {
+        let router = router(4);
+        let channels = channels(8);
+        let (client, _receiver) = subscriber();
+
+        router.subscribe(channels.iter().step_by(3).cloned().collect(), client, None);
+
+        let online = router.channel_stats(channels).await;
+
+        assert_eq!(online, vec![true, false, false, true, false, false, true, false]);
+    }
+
+    #[actix_web::test]
+    async fn test_unsubscribe_removes_client() {
+        let router = router(2);
+        let channels = channels(2);
+
+        let mut subscribers = Vec::new();
+        for _ in 0..2 {
+            let (client, receiver) = subscriber();
+            router.subscribe(channels.clone(), client.clone(), None);
+            subscribers.push((client, receiver));
+        }
+
+        router.unsubscribe(channels.clone(), subscribers[0].0.clone());
+        router.publish(channels.clone(), message());
+
+        let stats = router.server_stats().await.unwrap();
+
+        assert_eq!(stats.clients, 1);
+        assert_eq!(stats.subscribers, 2);
+        assert_eq!(stats.messages.delivered, 2);
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert!(subscribers[0].1.try_recv().is_err());
+        assert!(subscribers[1].1.try_recv().is_ok());
+
+        router.unsubscribe(channels.clone(), subscribers[1].0.clone());
+
+        assert_eq!(router.channel_stats(channels).await, vec![false, false]);
+        assert_eq!(router.server_stats().await.unwrap().channels, 0);
+    }
+}
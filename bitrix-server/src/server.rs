@@ -1,83 +1,290 @@
 use std::collections::HashMap;
+use std::sync::atomic::Ordering;
 
-use crate::message::{ProtobufMessage, SendPullMessage, SubscribeChannelMessage};
+use crate::{
+    message::{
+        Client, KickMessage, RegisterSessionMessage, ServerStatsMessage, SessionFilter, SessionsMessage,
+        ShutdownMessage, UnregisterSessionMessage,
+    },
+    stats::{ServerStats, SessionStats},
+    utils,
+};
 use actix::prelude::*;
-use actix_broker::BrokerSubscribe;
+use actix_web_actors::ws::CloseReason;
 use bitrix_channels::Channel;
+use uuid::Uuid;
 
-type Client = Recipient<ProtobufMessage>;
-type Subscribers = Vec<Client>;
+/// Subscriber with what it subscribed to and when
+struct Session {
+    client: Client,
+    channels: Vec<Channel>,
+    since: u32,
+}
 
+/// Registry of live sessions for the admin API, stats and shutdown.
+///
+/// Publishes and subscriptions never pass through it, `ShardRouter` sends
+/// them to the shards directly and only tells the server who came and went.
 #[derive(Default)]
 pub struct WsPullServer {
-    channels: HashMap<String, Subscribers>,
+    sessions: HashMap<Uuid, Session>,
+    /// Set once the process is going down, new sessions are closed with it
+    closing: Option<CloseReason>,
 }
 
 impl WsPullServer {
-    fn take_subscribers(&mut self, channel_name: Channel) -> Option<Subscribers> {
-        let subscribers = self.channels.get_mut(&channel_name.to_string())?;
-        let subscribers = std::mem::take(subscribers);
-        Some(subscribers)
-    }
+    /// Live sessions matching the filter
+    fn select<'a>(&'a self, filter: &'a SessionFilter) -> Box<dyn Iterator<Item = &'a Session> + 'a> {
+        let connected = |session: &&Session| session.client.connected();
 
-    fn add_client_to_channel(&mut self, channel_name: Channel, client: Client) {
-        let subscribers = self.channels.entry(channel_name.to_string()).or_default();
-        subscribers.push(client);
+        match filter {
+            SessionFilter::All => Box::new(self.sessions.values().filter(connected)),
+            SessionFilter::Id(id) => Box::new(self.sessions.get(id).into_iter().filter(connected)),
+            SessionFilter::Channel(channel_name) => Box::new(self.sessions.values().filter(connected).filter(
+                move |session| {
+                    session
+                        .channels
+                        .iter()
+                        .any(|subscribed| subscribed.to_string() == channel_name.to_string())
+                },
+            )),
+        }
     }
+}
+
+impl Actor for WsPullServer {
+    type Context = Context<Self>;
+}
+
+impl Handler<RegisterSessionMessage> for WsPullServer {
+    type Result = ();
 
-    fn send_pull_message(&mut self, channel_name: Channel, msg: ProtobufMessage) -> Option<()> {
-        let mut subscribers = self.take_subscribers(channel_name.clone())?;
+    fn handle(&mut self, msg: RegisterSessionMessage, _ctx: &mut Self::Context) {
+        let RegisterSessionMessage(client, channels) = msg;
 
-        for client in subscribers.drain(..) {
-            match client.try_send(msg.clone()) {
-                Ok(()) => self.add_client_to_channel(channel_name.clone(), client),
-                Err(error_text) => {
-                    log::debug!("WsPullServer::send_pull_message => Channel: {channel_name:?} => {error_text:?}");
-                }
-            };
+        if let Some(reason) = &self.closing {
+            client.disconnect(reason.clone());
+            return;
         }
 
-        Some(())
+        let id = client.info().id;
+
+        match self.sessions.get_mut(&id) {
+            Some(session) => session.channels.extend(channels),
+            None => {
+                self.sessions.insert(
+                    id,
+                    Session {
+                        client,
+                        channels,
+                        since: utils::get_timestamp(),
+                    },
+                );
+            }
+        }
     }
 }
 
-impl Actor for WsPullServer {
-    type Context = Context<Self>;
+impl Handler<UnregisterSessionMessage> for WsPullServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: UnregisterSessionMessage, _ctx: &mut Self::Context) {
+        let UnregisterSessionMessage(client) = msg;
 
-    fn started(&mut self, ctx: &mut Self::Context) {
-        self.subscribe_system_async::<SendPullMessage>(ctx);
+        self.sessions.remove(&client.info().id);
     }
 }
 
-impl Handler<SubscribeChannelMessage> for WsPullServer {
-    type Result = MessageResult<SubscribeChannelMessage>;
+impl Handler<ServerStatsMessage> for WsPullServer {
+    type Result = MessageResult<ServerStatsMessage>;
 
-    fn handle(&mut self, msg: SubscribeChannelMessage, _ctx: &mut Self::Context) -> Self::Result {
-        let SubscribeChannelMessage(channels, client) = msg;
+    /// Counters of channels and messages are added by `ShardRouter::server_stats`
+    fn handle(&mut self, _msg: ServerStatsMessage, _ctx: &mut Self::Context) -> Self::Result {
+        self.sessions.retain(|_, session| session.client.connected());
 
-        for channel_name in channels {
-            self.add_client_to_channel(channel_name.clone(), client.clone());
+        MessageResult(ServerStats {
+            pid: std::process::id(),
+            date: utils::get_timestamp(),
+            clients: self.sessions.len(),
+            ..Default::default()
+        })
+    }
+}
+
+impl Handler<ShutdownMessage> for WsPullServer {
+    type Result = MessageResult<ShutdownMessage>;
+
+    fn handle(&mut self, msg: ShutdownMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let ShutdownMessage(reason) = msg;
+
+        let sessions = std::mem::take(&mut self.sessions);
+
+        for session in sessions.values() {
+            session.client.disconnect(reason.clone());
         }
 
-        MessageResult(())
+        self.closing = Some(reason);
+
+        MessageResult(sessions.len())
     }
 }
 
-impl Handler<SendPullMessage> for WsPullServer {
-    type Result = ();
+impl Handler<SessionsMessage> for WsPullServer {
+    type Result = MessageResult<SessionsMessage>;
+
+    fn handle(&mut self, msg: SessionsMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let SessionsMessage(filter) = msg;
 
-    fn handle(&mut self, msg: SendPullMessage, _ctx: &mut Self::Context) {
-        let SendPullMessage(channel_names, protobuf_msg) = msg;
+        MessageResult(
+            self.select(&filter)
+                .map(|session| {
+                    let info = session.client.info();
 
-        log::debug!(
-            "WsPullServer[Handler[<SendPullMessage>]]::handle => channel_names {channel_names:?}"
-        );
+                    SessionStats {
+                        id: info.id.to_string(),
+                        kind: info.kind,
+                        channels: session.channels.iter().map(ToString::to_string).collect(),
+                        connected: session.since,
+                        remote_addr: info.remote_addr.clone(),
+                        sent: info.sent.load(Ordering::Relaxed),
+                    }
+                })
+                .collect(),
+        )
+    }
+}
 
-        for channel_name in channel_names {
-            self.send_pull_message(channel_name.clone(), protobuf_msg.clone());
+impl Handler<KickMessage> for WsPullServer {
+    type Result = MessageResult<KickMessage>;
+
+    fn handle(&mut self, msg: KickMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let KickMessage(filter, reason) = msg;
+
+        let clients = self.select(&filter).map(|session| &session.client).collect::<Vec<_>>();
+
+        for client in &clients {
+            client.disconnect(reason.clone());
         }
+
+        MessageResult(clients.len())
     }
 }
 
 impl SystemService for WsPullServer {}
 impl Supervised for WsPullServer {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::message::{ChannelMessage, DisconnectMessage, SessionInfo, SessionKind};
+
+    struct Subscriber;
+
+    impl Actor for Subscriber {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<ChannelMessage> for Subscriber {
+        type Result = ();
+
+        fn handle(&mut self, _msg: ChannelMessage, _ctx: &mut Self::Context) {}
+    }
+
+    impl Handler<DisconnectMessage> for Subscriber {
+        type Result = ();
+
+        fn handle(&mut self, _msg: DisconnectMessage, ctx: &mut Self::Context) {
+            ctx.stop();
+        }
+    }
+
+    fn channels(count: usize) -> Vec<Channel> {
+        (0..count)
+            .map(|number| Channel::create_private(format!("{number:032x}")))
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn test_unregister_removes_session() {
+        let server = WsPullServer::default().start();
+
+        let clients = (0..3).map(|_| Client::new(Subscriber.start())).collect::<Vec<_>>();
+        for client in &clients {
+            server.send(RegisterSessionMessage(client.clone(), channels(2))).await.unwrap();
+        }
+
+        /* The same session subscribing again is still one session */
+        server.send(RegisterSessionMessage(clients[2].clone(), channels(1))).await.unwrap();
+        server.send(UnregisterSessionMessage(clients[0].clone())).await.unwrap();
+
+        let sessions = server.send(SessionsMessage(SessionFilter::All)).await.unwrap();
+
+        assert_eq!(server.send(ServerStatsMessage).await.unwrap().clients, 2);
+        assert!(sessions.iter().all(|session| session.id != clients[0].info().id.to_string()));
+        assert!(sessions.iter().any(|session| session.channels.len() == 3));
+    }
+
+    #[actix_web::test]
+    async fn test_shutdown_closes_sessions() {
+        let server = WsPullServer::default().start();
+        let reason = CloseReason::from(actix_web_actors::ws::CloseCode::Away);
+
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let client = Client::new(Subscriber.start());
+            server.send(RegisterSessionMessage(client.clone(), channels(4))).await.unwrap();
+            clients.push(client);
+        }
+
+        assert_eq!(server.send(ShutdownMessage(reason)).await.unwrap(), 2);
+
+        /* Subscribed after the shutdown started */
+        let late = Client::new(Subscriber.start());
+        server.send(RegisterSessionMessage(late.clone(), channels(4))).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert!(clients.iter().all(|client| !client.connected()));
+        assert!(!late.connected());
+        assert_eq!(server.send(ServerStatsMessage).await.unwrap().clients, 0);
+    }
+
+    #[actix_web::test]
+    async fn test_sessions_and_kick() {
+        let server = WsPullServer::default().start();
+        let all = channels(3);
+
+        let mut clients = Vec::new();
+        for subscribed in [&all[..2], &all[1..]] {
+            let info = Arc::new(SessionInfo::new(SessionKind::WebSocket, Some("10.0.0.1:5000".to_string())));
+            let client = Client::new(Subscriber.start()).with_info(info);
+            server.send(RegisterSessionMessage(client.clone(), subscribed.to_vec())).await.unwrap();
+            clients.push(client);
+        }
+
+        let sessions = server.send(SessionsMessage(SessionFilter::All)).await.unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions.iter().all(|session| session.remote_addr.as_deref() == Some("10.0.0.1:5000")));
+
+        let first = clients[0].info().id;
+        let only_first = Channel::create_unknown(all[0].to_string());
+        let sessions = server.send(SessionsMessage(SessionFilter::Channel(only_first.clone()))).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, first.to_string());
+        assert_eq!(sessions[0].channels.len(), 2);
+
+        let shared = Channel::create_unknown(all[1].to_string());
+        let reason = CloseReason::from(actix_web_actors::ws::CloseCode::Policy);
+        assert_eq!(server.send(KickMessage(SessionFilter::Id(first), reason.clone())).await.unwrap(), 1);
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert!(!clients[0].connected());
+        assert!(clients[1].connected());
+        assert_eq!(server.send(KickMessage(SessionFilter::Channel(only_first), reason.clone())).await.unwrap(), 0);
+        assert_eq!(server.send(KickMessage(SessionFilter::Channel(shared), reason)).await.unwrap(), 1);
+    }
+}
@@ -1,110 +1,370 @@
-use actix::{fut, prelude::*};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
 use actix_web_actors::ws;
-use bitrix_actix_protobuf;
+use bytes::Bytes;
 use prost::Message;
-use uuid::Uuid;
 
-use bitrix_channels::Channel;
+use bitrix_channels::{Channel, ChannelType, Parser};
 
 use crate::{
-    message::{ProtobufMessage, SubscribeChannelMessage},
-    server::WsPullServer,
+    coalesce::Coalescer,
+    items,
+    message::{
+        ChannelMessage, Client, DisconnectMessage, SessionInfo, SessionKind,
+    },
+    logging::{self, Redacted},
+    metrics::METRICS,
+    processor::{self, Origin},
+    protocol::{self, Protocol},
+    reload::Swap,
+    router::ShardRouter,
+    settings, utils,
 };
 
-#[derive(Debug)]
 pub struct WsSession {
-    id: Uuid,
+    router: ShardRouter,
+    info: Arc<SessionInfo>,
     pub channels: Vec<Channel>,
+    parser: Arc<Swap<Parser>>,
+    protocol: Protocol,
+    last_message_id: Option<Vec<u8>>,
+    heartbeat: Instant,
+    heartbeat_interval: Duration,
+    client_timeout: Duration,
+    batch: Coalescer,
+    batch_max_delay: Duration,
+    batch_flush: Option<SpawnHandle>,
 }
 
 impl WsSession {
-    pub fn get_target(&self) -> String {
-        format!("WsSession:{}",self.id.clone())
+    pub fn new(router: ShardRouter) -> Self {
+        WsSession {
+            router,
+            info: Arc::new(SessionInfo::new(SessionKind::WebSocket, None)),
+            channels: Vec::new(),
+            parser: Arc::default(),
+            protocol: Protocol::Binary,
+            last_message_id: None,
+            heartbeat: Instant::now(),
+            heartbeat_interval: Duration::from_secs(settings::WebSocket::default().heartbeat_interval),
+            client_timeout: Duration::from_secs(settings::WebSocket::default().client_timeout),
+            batch: Coalescer::new(settings::WebSocket::default().batch_max_size),
+            batch_max_delay: Duration::from_millis(settings::WebSocket::default().batch_max_delay),
+            batch_flush: None,
+        }
     }
+
     pub fn get_channels(&self) -> Vec<Channel> {
         self.channels.clone()
     }
     pub fn set_channels(&mut self, channels: Vec<Channel>) {
         self.channels = channels;
     }
-}
+    pub fn get_private_channel(&self) -> Option<Channel> {
+        self.channels
+            .iter()
+            .find(|channel| channel.get_kind() == ChannelType::Private)
+            .cloned()
+    }
+    /// Client address for the admin API, before the session starts
+    pub fn set_remote_addr(&mut self, remote_addr: Option<String>) {
+        self.info = Arc::new(SessionInfo::new(SessionKind::WebSocket, remote_addr));
+    }
+    pub fn set_parser(&mut self, parser: Arc<Swap<Parser>>) {
+        self.parser = parser;
+    }
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+    pub fn set_last_message_id(&mut self, last_message_id: Option<Vec<u8>>) {
+        self.last_message_id = last_message_id;
+    }
+    pub fn set_heartbeat(&mut self, heartbeat_interval: Duration, client_timeout: Duration) {
+        self.heartbeat_interval = heartbeat_interval;
+        self.client_timeout = client_timeout;
+    }
+    pub fn set_batching(&mut self, batch_max_size: usize, batch_max_delay: Duration) {
+        self.batch = Coalescer::new(batch_max_size);
+        self.batch_max_delay = batch_max_delay;
+    }
 
-impl Default for WsSession {
-    fn default() -> Self {
-        WsSession {
-            id: Uuid::new_v4(),
-            channels: Vec::new()
+    /// Ping the client every `heartbeat_interval` and drop it after `client_timeout` of silence
+    fn start_heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.run_interval(self.heartbeat_interval, |act, ctx| {
+            if Instant::now().duration_since(act.heartbeat) > act.client_timeout {
+                log::debug!(session:% = act.info.id; "Client heartbeat timeout, disconnecting");
+                ctx.stop();
+                return;
+            }
+
+            ctx.ping(b"");
+        });
+    }
+
+    /// Send the frame right away or hold it up to `batch_max_delay` to merge with the next ones
+    fn send_frame(&mut self, frame: Bytes, messages: usize, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.batch_max_delay.is_zero() {
+            ctx.binary(frame);
+            return;
+        }
+
+        if self.batch.push(frame, messages) {
+            self.flush_frames(ctx);
+            return;
+        }
+
+        if self.batch_flush.is_none() {
+            self.batch_flush = Some(ctx.run_later(self.batch_max_delay, |act, ctx| {
+                act.batch_flush = None;
+                act.flush_frames(ctx);
+            }));
+        }
+    }
+
+    fn flush_frames(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        if let Some(handle) = self.batch_flush.take() {
+            ctx.cancel_future(handle);
+        }
+
+        if let Some(frame) = self.batch.take() {
+            ctx.binary(frame);
+        }
+    }
+
+    fn send_batch(&self, batch: &items::ResponseBatch, ctx: &mut ws::WebsocketContext<Self>) {
+        let encode_result = protocol::encode_binary(batch)
+            .map_err(bitrix_actix_protobuf::ProtoBufPayloadError::Serialize);
+
+        match encode_result {
+            Ok(body) => ctx.binary(body),
+            Err(error) => {
+                log::error!(session:% = self.info.id; "Couldn't encode message: {error:#?}");
+            }
         }
     }
+
+    /// Client sent a `RequestBatch`, process it as an untrusted request
+    fn process_client_request(&self, body: &[u8], ctx: &mut ws::WebsocketContext<Self>) {
+        let request_batch = match items::RequestBatch::decode(body) {
+            Ok(request_batch) => request_batch,
+            Err(error) => {
+                log::error!(session:% = self.info.id; "Got binary that couldn't decode. Error: {error}");
+                return;
+            }
+        };
+
+        log::debug!(session:% = self.info.id, request:% = Redacted(&request_batch); "Parsed protobuf");
+
+        let origin = Origin::Client(self.get_private_channel());
+        let parser = self.parser.load();
+        let router = self.router.clone();
+
+        ctx.spawn(
+            async move { processor::process_requests(request_batch.requests, &origin, &parser, &router).await }
+                .into_actor(self)
+                .map(|result, act, ctx| match result {
+                    Ok(responses) if !responses.is_empty() => {
+                        act.send_batch(&items::ResponseBatch { responses }, ctx);
+                    }
+                    Ok(_) => {}
+                    Err(error) => {
+                        log::error!(session:% = act.info.id; "Couldn't process client request: {error}");
+                    }
+                }),
+        );
+    }
 }
 
 impl Actor for WsSession {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        log::debug!(target: self.get_target().as_str(), "Started");
+        log::debug!(session:% = self.info.id, channels:% = logging::channel_ids(&self.channels); "Started");
+
+        METRICS.websocket_opened();
+
+        self.start_heartbeat(ctx);
 
-        WsPullServer::from_registry()
-            .send(
-                SubscribeChannelMessage(
-                    self.channels.clone(),
-                    ctx.address().recipient()
-                )
-            )
-            .into_actor(self)
-            .then(|_id, _act, _ctx| fut::ready(()))
-            .wait(ctx);
+        self.router.subscribe(
+            self.get_channels(),
+            Client::new(ctx.address()).with_info(self.info.clone()),
+            self.last_message_id.take(),
+        );
     }
 
-    fn stopped(&mut self, _ctx: &mut Self::Context) {
-        log::debug!(target: self.get_target().as_str(), "Stopped");
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        log::debug!(session:% = self.info.id; "Stopped");
+
+        METRICS.websocket_closed();
+
+        /* Stopped without a close frame, e.g. on heartbeat timeout */
+        self.flush_frames(ctx);
+
+        self.router.unsubscribe(
+            self.get_channels(),
+            Client::new(ctx.address()).with_info(self.info.clone()),
+        );
     }
 }
 
-impl Handler<ProtobufMessage> for WsSession {
+impl Handler<ChannelMessage> for WsSession {
     type Result = ();
 
-    fn handle(&mut self, msg: ProtobufMessage, ctx: &mut Self::Context) {
-        let mut body = Vec::new();
-        let encode_result = msg
-            .0
-            .encode(&mut body)
-            .map_err(bitrix_actix_protobuf::ProtoBufPayloadError::Serialize);
+    fn handle(&mut self, msg: ChannelMessage, ctx: &mut Self::Context) {
+        let ChannelMessage(channel, protobuf_msg) = msg;
 
-        if encode_result.is_ok() {
-            ctx.binary(body);
-        } else {
-            log::error!(target: self.get_target().as_str(), "Couldn't encode message: {encode_result:#?}");
+        let protobuf_msg = match protobuf_msg.without_expired(utils::get_timestamp()) {
+            Some(protobuf_msg) => protobuf_msg,
+            None => {
+                log::trace!(session:% = self.info.id; "Message expired in mailbox");
+                return;
+            }
+        };
+
+        match self.protocol {
+            Protocol::Binary => {
+                let messages = utils::get_outgoing_messages(protobuf_msg.batch()).count();
+                self.info.add_sent(messages);
+                self.send_frame(protobuf_msg.frame(), messages, ctx);
+            }
+            Protocol::Text { with_mid } => {
+                self.info.add_sent(utils::get_outgoing_messages(protobuf_msg.batch()).count());
+                ctx.text(protocol::encode_text(&channel, &protobuf_msg, with_mid));
+            }
         }
     }
 }
 
+impl Handler<DisconnectMessage> for WsSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: DisconnectMessage, ctx: &mut Self::Context) {
+        log::debug!(session:% = self.info.id; "Disconnect: {:?}", msg.0);
+
+        /* Nothing may follow the close frame */
+        self.flush_frames(ctx);
+        ctx.close(Some(msg.0));
+        ctx.stop();
+    }
+}
+
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         let msg = match msg {
             Err(err) => {
-                log::error!(target: self.get_target().as_str(), "stream handler error: {}", err);
+                log::error!(session:% = self.info.id; "stream handler error: {}", err);
                 ctx.stop();
                 return;
             }
             Ok(msg) => msg,
         };
 
+        self.heartbeat = Instant::now();
+
         match msg {
             ws::Message::Ping(msg) => ctx.pong(&msg),
             ws::Message::Close(reason) => {
+                self.flush_frames(ctx);
                 ctx.close(reason);
                 ctx.stop();
             },
             ws::Message::Text(_) => {
-                log::error!(target: self.get_target().as_str(), "We don't support 'text' message type now");
-                log::trace!(target: self.get_target().as_str(), "Message: {msg:?}");
-            },
-            ws::Message::Binary(_) => {
-                log::error!(target: self.get_target().as_str(), "We don't support 'binary' message type now");
-                log::trace!(target: self.get_target().as_str(), "Message: {msg:?}");
+                log::debug!(session:% = self.info.id; "We don't support 'text' message type now");
+                log::trace!(session:% = self.info.id, message:% = Redacted(&msg); "Text message");
             },
+            ws::Message::Binary(body) => self.process_client_request(&body, ctx),
             _ => {}
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::error::PayloadError;
+    use futures_util::stream::{self, StreamExt as _};
+
+    use super::*;
+
+    /// Server frames are not masked: `FIN | opcode`, then the payload length
+    const PING: [u8; 2] = [0x89, 0x00];
+
+    async fn frames(session: WsSession) -> Vec<u8> {
+        let output = ws::WebsocketContext::create(session, stream::pending::<Result<Bytes, PayloadError>>());
+
+        tokio::time::timeout(Duration::from_secs(1), output.collect::<Vec<_>>())
+            .await
+            .expect("session is still open")
+            .into_iter()
+            .flat_map(Result::unwrap)
+            .collect()
+    }
+
+    /// Opcodes of the frames in `output`, payloads are short enough for a one byte length
+    fn opcodes(output: &[u8]) -> Vec<u8> {
+        let mut opcodes = Vec::new();
+        let mut position = 0;
+
+        while position < output.len() {
+            opcodes.push(output[position] & 0x0f);
+            position += 2 + (output[position + 1] & 0x7f) as usize;
+        }
+
+        opcodes
+    }
+
+    fn message(id: u8) -> ChannelMessage {
+        let batch = items::ResponseBatch {
+            responses: vec![items::Response {
+                command: Some(items::response::Command::OutgoingMessages(
+                    items::OutgoingMessagesResponse {
+                        messages: vec![items::OutgoingMessage {
+                            id: vec![id],
+                            ..Default::default()
+                        }],
+                    },
+                )),
+            }],
+        };
+
+        ChannelMessage(
+            Channel::create_private("f0e5d42369441879d7e176c96cbbff2d".to_string()),
+            crate::message::ProtobufMessage::new(batch),
+        )
+    }
+
+    #[actix_web::test]
+    async fn test_pending_batch_is_sent_before_close() {
+        let mut session = WsSession::new(ShardRouter::default());
+        session.set_batching(10, Duration::from_secs(5));
+
+        let (address, output) =
+            ws::WebsocketContext::create_with_addr(session, stream::pending::<Result<Bytes, PayloadError>>());
+
+        address.do_send(message(1));
+        address.do_send(message(2));
+        address.do_send(DisconnectMessage(ws::CloseReason::from(ws::CloseCode::Away)));
+
+        let output = tokio::time::timeout(Duration::from_secs(1), output.collect::<Vec<_>>())
+            .await
+            .expect("session is still open")
+            .into_iter()
+            .flat_map(Result::unwrap)
+            .collect::<Vec<_>>();
+
+        /* One binary frame with both messages, then the close frame */
+        assert_eq!(opcodes(&output), vec![0x2, 0x8], "{output:x?}");
+    }
+
+    #[actix_web::test]
+    async fn test_heartbeat_timeout_closes_idle_session() {
+        let mut session = WsSession::new(ShardRouter::default());
+        session.set_heartbeat(Duration::from_millis(10), Duration::from_millis(35));
+
+        let output = frames(session).await;
+
+        assert!(!output.is_empty());
+        assert!(output.chunks(2).all(|frame| frame == PING), "{output:x?}");
+    }
+}
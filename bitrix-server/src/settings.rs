@@ -1,12 +1,30 @@
+use bitrix_channels::{Parser, Signature};
 use config::{Config, ConfigError, Environment, File};
 use serde::Deserialize;
 use std::env;
+use std::path::Path;
 
 #[derive(Debug, Deserialize, Clone)]
 #[allow(unused)]
 pub struct General {
     pub port: u16,
     pub workers: usize,
+    /// Arbiters the channel map is split across, one per core by default
+    #[serde(default = "General::default_shards")]
+    pub shards: usize,
+    /// Seconds to wait for connections to close on SIGTERM/SIGINT before exiting
+    #[serde(default = "General::default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+}
+
+impl General {
+    pub fn default_shards() -> usize {
+        std::thread::available_parallelism().map_or(1, |cores| cores.get())
+    }
+
+    fn default_shutdown_timeout() -> u64 {
+        5
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -16,10 +34,187 @@ pub struct Security {
     pub key: String,
 }
 
+impl Security {
+    /// Channel parser checking signatures with `key` when `enabled`.
+    ///
+    /// The key is kept either way, client publishes are always checked.
+    pub fn parser(&self) -> Parser {
+        Parser::new(self.enabled, Signature::new(self.key.clone()))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// env_logger lines with `key=value` fields
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 #[allow(unused)]
 pub struct Log {
     pub level: String,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Write message bodies and whole requests to the log, they are redacted otherwise
+    #[serde(default)]
+    pub debug_bodies: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct Polling {
+    /// Seconds to hold a long polling request open before answering 304
+    pub timeout: u64,
+}
+
+impl Default for Polling {
+    fn default() -> Self {
+        Polling { timeout: 40 }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct History {
+    /// Messages kept per channel for replay on reconnect
+    pub size: usize,
+    /// Channels kept at most, the least recently published ones are forgotten first
+    #[serde(default = "History::default_channels")]
+    pub channels: usize,
+}
+
+impl History {
+    fn default_channels() -> usize {
+        10_000
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            size: 100,
+            channels: History::default_channels(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct WebSocket {
+    /// Seconds between server pings
+    pub heartbeat_interval: u64,
+    /// Seconds of client silence before the session is dropped
+    pub client_timeout: u64,
+    /// Milliseconds a binary session waits for more messages to send them in one frame, zero sends at once
+    #[serde(default)]
+    pub batch_max_delay: u64,
+    /// Messages in one frame, the batch goes out as soon as it is reached
+    #[serde(default = "WebSocket::default_batch_max_size")]
+    pub batch_max_size: usize,
+}
+
+impl WebSocket {
+    fn default_batch_max_size() -> usize {
+        100
+    }
+}
+
+impl Default for WebSocket {
+    fn default() -> Self {
+        WebSocket {
+            heartbeat_interval: 30,
+            client_timeout: 90,
+            batch_max_delay: 0,
+            batch_max_size: WebSocket::default_batch_max_size(),
+        }
+    }
+}
+
+/// What to do when a subscriber doesn't keep up and its mailbox is full
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// Hold up to `buffer_size` messages, forget the oldest ones beyond that
+    DropOldest,
+    /// Close the connection right away
+    Disconnect,
+    /// Hold up to `buffer_size` messages, close the connection beyond that
+    Buffer,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[allow(unused)]
+pub struct Backpressure {
+    pub policy: SlowConsumerPolicy,
+    /// Messages held for one subscriber while its mailbox is full
+    pub buffer_size: usize,
+}
+
+impl Default for Backpressure {
+    fn default() -> Self {
+        Backpressure {
+            policy: SlowConsumerPolicy::DropOldest,
+            buffer_size: 100,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[allow(unused)]
+pub struct Tls {
+    /// PEM certificate chain, the server certificate goes first
+    pub cert_path: String,
+    /// PEM private key
+    pub key_path: String,
+    /// PEM certificates of CAs allowed to sign client certificates, no client auth when empty
+    pub client_ca_path: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[allow(unused)]
+pub struct Cluster {
+    /// Address other instances connect to, e.g. `10.0.0.1:9100`. Single instance when empty.
+    pub listen: Option<String>,
+    /// Addresses of every other instance of the cluster, nobody else may connect
+    #[serde(default)]
+    pub peers: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[allow(unused)]
+pub struct Metrics {
+    /// Address of the Prometheus `/metrics` endpoint, e.g. `127.0.0.1:9102`. Off when empty.
+    pub listen: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[allow(unused)]
+pub struct Inspector {
+    /// Serve the live traffic page at `/inspector/`, meant for development only
+    #[serde(default)]
+    pub enabled: bool,
+    /// Directory with the page files instead of the built-in page, relative to the config file
+    #[serde(default)]
+    pub static_dir: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[allow(unused)]
+pub struct Access {
+    /// Networks allowed to call publish, stats and admin endpoints, e.g. `10.0.0.0/8`. Anyone when empty.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Require `X-PUSH-SIGNATURE` and `X-PUSH-TIMESTAMP`, see `access::signed_content`
+    #[serde(default)]
+    pub require_signature: bool,
+    /// Reverse proxies in front of the server, e.g. `127.0.0.1`. The client address is taken
+    /// from their `X-Forwarded-For`, the header of anybody else is ignored.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -28,17 +223,46 @@ pub struct Settings {
     pub security: Security,
     pub log: Log,
     pub general: General,
+    #[serde(default)]
+    pub polling: Polling,
+    #[serde(default)]
+    pub history: History,
+    #[serde(default)]
+    pub websocket: WebSocket,
+    #[serde(default)]
+    pub backpressure: Backpressure,
+    #[serde(default)]
+    pub cluster: Cluster,
+    #[serde(default)]
+    pub access: Access,
+    #[serde(default)]
+    pub metrics: Metrics,
+    #[serde(default)]
+    pub inspector: Inspector,
+    /// Plain HTTP when absent
+    pub tls: Option<Tls>,
 }
 
 impl Settings {
     pub fn new() -> Result<Self, ConfigError> {
         let config_file = env::var("CONFIG_FILE").unwrap_or_else(|_| "./push_config.toml".into());
 
+        Settings::from_file(&config_file)
+    }
+
+    /// Read `config_file`, `PUSH_*` environment variables override it
+    pub fn from_file(config_file: &str) -> Result<Self, ConfigError> {
         let s = Config::builder()
-            .add_source(File::with_name(&config_file).required(true))
+            .add_source(File::with_name(config_file).required(true))
             .add_source(Environment::with_prefix("push"))
             .build()?;
 
-        s.try_deserialize()
+        let mut settings: Settings = s.try_deserialize()?;
+
+        if let (Some(static_dir), Some(config_dir)) = (&settings.inspector.static_dir, Path::new(config_file).parent()) {
+            settings.inspector.static_dir = Some(config_dir.join(static_dir).to_string_lossy().into_owned());
+        }
+
+        Ok(settings)
     }
 }
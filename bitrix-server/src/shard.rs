@@ -0,0 +1,548 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use actix::prelude::*;
+use actix_web_actors::ws::{CloseCode, CloseReason};
+use bitrix_channels::Channel;
+
+use crate::{
+    history::{self, History},
+    message::{
+        ChannelMessage, ChannelStatsMessage, Client, HistorySequenceMessage, ProtobufMessage,
+        SendPullMessage, ShardStatsMessage, SubscribeChannelMessage, UnsubscribeChannelMessage,
+    },
+    metrics::METRICS,
+    settings::{Backpressure, SlowConsumerPolicy},
+    stats::ShardStats,
+    utils,
+};
+
+/// How often messages held for slow subscribers are offered again
+const BACKLOG_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+const HISTORY_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+type Subscribers = Vec<Client>;
+
+/// Part of the channel map, runs on its own arbiter.
+///
+/// `ShardRouter` sends every channel to exactly one shard, so the fan-out of
+/// a crowded channel doesn't hold up subscribers of the other shards. The
+/// shard keeps the history of its channels as well.
+#[derive(Default)]
+pub struct WsPullShard {
+    channels: HashMap<String, Subscribers>,
+    backpressure: Backpressure,
+    history: History,
+    /// Messages for subscribers with a full mailbox, in publish order
+    backlogs: HashMap<Client, VecDeque<ChannelMessage>>,
+    delivered: u64,
+    failed: u64,
+    dropped: u64,
+    disconnected: u64,
+}
+
+impl WsPullShard {
+    pub fn new(backpressure: Backpressure, history: History) -> Self {
+        WsPullShard {
+            backpressure,
+            history,
+            ..Default::default()
+        }
+    }
+
+    fn take_subscribers(&mut self, channel_name: Channel) -> Option<Subscribers> {
+        let subscribers = self.channels.get_mut(&channel_name.to_string())?;
+        let subscribers = std::mem::take(subscribers);
+        Some(subscribers)
+    }
+
+    fn add_client_to_channel(&mut self, channel_name: Channel, client: Client) {
+        let subscribers = self.channels.entry(channel_name.to_string()).or_default();
+        subscribers.push(client);
+    }
+
+    fn remove_client_from_channel(&mut self, channel_name: Channel, client: &Client) {
+        let channel_name = channel_name.to_string();
+
+        if let Some(subscribers) = self.channels.get_mut(&channel_name) {
+            subscribers.retain(|subscriber| subscriber != client);
+
+            if subscribers.is_empty() {
+                self.channels.remove(&channel_name);
+            }
+        }
+    }
+
+    fn remember(&mut self, channels: &[Channel], msg: &ProtobufMessage) {
+        for (sequence, message) in msg.sequenced() {
+            self.history.push(sequence, channels, std::slice::from_ref(message));
+        }
+    }
+
+    /// Messages of `channels` published after `last_sequence`, as one batch to the first of them.
+    /// Goes the way of a live publish, so a slow client holds it in its backlog.
+    fn replay(&mut self, channels: &[Channel], client: &Client, last_sequence: u64) {
+        let entries = history::after(self.history.entries(channels), last_sequence, utils::get_timestamp());
+
+        let Some(channel_name) = channels.first().filter(|_| !entries.is_empty()) else {
+            return;
+        };
+
+        let msg = ChannelMessage(channel_name.clone(), ProtobufMessage::replayed(entries));
+
+        if !self.send_to_client(client, msg) {
+            for channel_name in channels {
+                self.remove_client_from_channel(channel_name.clone(), client);
+            }
+        }
+    }
+
+    fn send_pull_message(&mut self, channel_name: Channel, msg: ProtobufMessage) -> Option<()> {
+        let mut subscribers = self.take_subscribers(channel_name.clone())?;
+
+        for client in subscribers.drain(..) {
+            if self.send_to_client(&client, ChannelMessage(channel_name.clone(), msg.clone())) {
+                self.add_client_to_channel(channel_name.clone(), client);
+            }
+        }
+
+        if self.channels.get(&channel_name.to_string()).is_some_and(Vec::is_empty) {
+            self.channels.remove(&channel_name.to_string());
+        }
+
+        Some(())
+    }
+
+    /// Returns `false` if the client has to leave the channel
+    fn send_to_client(&mut self, client: &Client, msg: ChannelMessage) -> bool {
+        /* Client is already behind, the message waits its turn */
+        if self.backlogs.contains_key(client) {
+            return self.hold(client, msg);
+        }
+
+        let published = msg.1.published();
+
+        match client.try_send(msg) {
+            Ok(()) => {
+                self.delivered += 1;
+                METRICS.delivered(published.elapsed());
+                true
+            }
+            Err(SendError::Full(msg)) => match self.backpressure.policy {
+                SlowConsumerPolicy::Disconnect => {
+                    self.disconnect(client);
+                    false
+                }
+                SlowConsumerPolicy::DropOldest | SlowConsumerPolicy::Buffer => self.hold(client, msg),
+            },
+            Err(SendError::Closed(msg)) => {
+                self.failed += 1;
+                log::debug!("WsPullShard::send_to_client => Channel: {:?} => client is gone", msg.0);
+                false
+            }
+        }
+    }
+
+    /// Keep the message until the client's mailbox has room for it
+    fn hold(&mut self, client: &Client, msg: ChannelMessage) -> bool {
+        let backlog = self.backlogs.entry(client.clone()).or_default();
+
+        if backlog.len() >= self.backpressure.buffer_size {
+            match self.backpressure.policy {
+                SlowConsumerPolicy::DropOldest if self.backpressure.buffer_size > 0 => {
+                    backlog.pop_front();
+                    self.dropped += 1;
+                }
+                SlowConsumerPolicy::DropOldest => {
+                    self.backlogs.remove(client);
+                    self.dropped += 1;
+                    return true;
+                }
+                SlowConsumerPolicy::Disconnect | SlowConsumerPolicy::Buffer => {
+                    self.disconnect(client);
+                    return false;
+                }
+            }
+        }
+
+        backlog.push_back(msg);
+        true
+    }
+
+    /// Drop the client from every channel of the shard and ask it to close the connection
+    fn disconnect(&mut self, client: &Client) {
+        log::debug!("WsPullShard::disconnect => slow consumer");
+
+        self.dropped += self.backlogs.remove(client).map_or(0, |backlog| backlog.len() as u64);
+        self.disconnected += 1;
+
+        self.channels.retain(|_, subscribers| {
+            subscribers.retain(|subscriber| subscriber != client);
+            !subscribers.is_empty()
+        });
+
+        client.disconnect(CloseReason {
+            code: CloseCode::Again,
+            description: Some("Slow consumer".to_string()),
+        });
+    }
+
+    /// Offer held messages again, in order, as long as the mailbox takes them
+    fn flush_backlogs(&mut self) {
+        for (client, mut backlog) in std::mem::take(&mut self.backlogs) {
+            while let Some(msg) = backlog.pop_front() {
+                let published = msg.1.published();
+
+                match client.try_send(msg) {
+                    Ok(()) => {
+                        self.delivered += 1;
+                        METRICS.delivered(published.elapsed());
+                    }
+                    Err(SendError::Full(msg)) => {
+                        backlog.push_front(msg);
+                        break;
+                    }
+                    Err(SendError::Closed(_)) => {
+                        self.failed += backlog.len() as u64 + 1;
+                        backlog.clear();
+                    }
+                }
+            }
+
+            if !backlog.is_empty() {
+                self.backlogs.insert(client, backlog);
+            }
+        }
+    }
+
+    fn is_online(&self, channel_name: &Channel) -> bool {
+        self.channels
+            .get(&channel_name.to_string())
+            .map(|subscribers| subscribers.iter().any(|client| client.connected()))
+            .unwrap_or(false)
+    }
+
+    fn get_stats(&self) -> ShardStats {
+        let connected = self
+            .channels
+            .values()
+            .map(|subscribers| subscribers.iter().filter(|client| client.connected()).count())
+            .filter(|count| *count > 0)
+            .collect::<Vec<_>>();
+
+        ShardStats {
+            channels: connected.len(),
+            subscribers: connected.iter().sum(),
+            delivered: self.delivered,
+            failed: self.failed,
+            dropped: self.dropped,
+            disconnected: self.disconnected,
+        }
+    }
+}
+
+impl Actor for WsPullShard {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(BACKLOG_RETRY_INTERVAL, |act, _ctx| act.flush_backlogs());
+
+        ctx.run_interval(HISTORY_PURGE_INTERVAL, |act, _ctx| {
+            act.history.purge_expired(utils::get_timestamp());
+        });
+    }
+}
+
+impl Handler<SubscribeChannelMessage> for WsPullShard {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeChannelMessage, _ctx: &mut Self::Context) {
+        let SubscribeChannelMessage(channels, client, last_sequence) = msg;
+
+        /* Stopped while the router looked up its last message */
+        if !client.connected() {
+            return;
+        }
+
+        for channel_name in &channels {
+            self.add_client_to_channel(channel_name.clone(), client.clone());
+        }
+
+        /* Same turn as the subscription, so the replay is queued before anything live */
+        if let Some(last_sequence) = last_sequence {
+            self.replay(&channels, &client, last_sequence);
+        }
+    }
+}
+
+impl Handler<HistorySequenceMessage> for WsPullShard {
+    type Result = Option<u64>;
+
+    fn handle(&mut self, msg: HistorySequenceMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let HistorySequenceMessage(channels, id) = msg;
+
+        history::sequence_of(&self.history.entries(&channels), &id)
+    }
+}
+
+impl Handler<UnsubscribeChannelMessage> for WsPullShard {
+    type Result = ();
+
+    fn handle(&mut self, msg: UnsubscribeChannelMessage, _ctx: &mut Self::Context) {
+        let UnsubscribeChannelMessage(channels, client) = msg;
+
+        for channel_name in channels {
+            self.remove_client_from_channel(channel_name, &client);
+        }
+
+        self.backlogs.remove(&client);
+    }
+}
+
+impl Handler<SendPullMessage> for WsPullShard {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendPullMessage, _ctx: &mut Self::Context) {
+        let SendPullMessage(channel_names, protobuf_msg) = msg;
+
+        self.remember(&channel_names, &protobuf_msg);
+
+        for channel_name in channel_names {
+            self.send_pull_message(channel_name, protobuf_msg.clone());
+        }
+    }
+}
+
+impl Handler<ChannelStatsMessage> for WsPullShard {
+    type Result = MessageResult<ChannelStatsMessage>;
+
+    fn handle(&mut self, msg: ChannelStatsMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let ChannelStatsMessage(channel_names) = msg;
+
+        MessageResult(
+            channel_names
+                .iter()
+                .map(|channel_name| self.is_online(channel_name))
+                .collect(),
+        )
+    }
+}
+
+impl Handler<ShardStatsMessage> for WsPullShard {
+    type Result = MessageResult<ShardStatsMessage>;
+
+    fn handle(&mut self, _msg: ShardStatsMessage, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.get_stats())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{items, message::DisconnectMessage};
+
+    enum Event {
+        Message(String),
+        Disconnect,
+    }
+
+    struct Subscriber(mpsc::UnboundedSender<Event>);
+
+    impl Actor for Subscriber {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<ChannelMessage> for Subscriber {
+        type Result = ();
+
+        fn handle(&mut self, msg: ChannelMessage, _ctx: &mut Self::Context) {
+            let ChannelMessage(_, protobuf_msg) = msg;
+            let body = crate::utils::get_outgoing_messages(protobuf_msg.batch())
+                .map(|message| message.body.clone())
+                .collect();
+
+            self.0.send(Event::Message(body)).unwrap();
+        }
+    }
+
+    impl Handler<DisconnectMessage> for Subscriber {
+        type Result = ();
+
+        fn handle(&mut self, _msg: DisconnectMessage, _ctx: &mut Self::Context) {
+            self.0.send(Event::Disconnect).unwrap();
+        }
+    }
+
+    /// Subscriber with room for one message, it doesn't run until the test yields
+    fn slow_subscriber() -> (Client, mpsc::UnboundedReceiver<Event>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut ctx = Context::new();
+        ctx.set_mailbox_capacity(1);
+
+        (Client::new(ctx.run(Subscriber(sender))), receiver)
+    }
+
+    fn shard(policy: SlowConsumerPolicy, buffer_size: usize) -> WsPullShard {
+        WsPullShard::new(Backpressure { policy, buffer_size }, History::default())
+    }
+
+    fn message(body: &str) -> ProtobufMessage {
+        ProtobufMessage::new(items::ResponseBatch {
+            responses: vec![items::Response {
+                command: Some(items::response::Command::OutgoingMessages(
+                    items::OutgoingMessagesResponse {
+                        messages: vec![items::OutgoingMessage {
+                            body: body.to_string(),
+                            ..Default::default()
+                        }],
+                    },
+                )),
+            }],
+        })
+    }
+
+    fn publish(shard: &mut WsPullShard, channel: &Channel, bodies: &[&str]) {
+        for body in bodies {
+            shard.send_pull_message(channel.clone(), message(body));
+        }
+    }
+
+    async fn drain(receiver: &mut mpsc::UnboundedReceiver<Event>) -> Vec<String> {
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        let mut events = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            events.push(match event {
+                Event::Message(body) => body,
+                Event::Disconnect => "disconnect".to_string(),
+            });
+        }
+        events
+    }
+
+    #[actix_web::test]
+    async fn test_drop_oldest() {
+        let channel = Channel::create_private("abc".to_string());
+        let (client, mut receiver) = slow_subscriber();
+        let mut shard = shard(SlowConsumerPolicy::DropOldest, 2);
+
+        shard.add_client_to_channel(channel.clone(), client);
+        publish(&mut shard, &channel, &["1", "2", "3", "4"]);
+
+        assert_eq!(shard.dropped, 1);
+        assert_eq!(drain(&mut receiver).await, vec!["1"]);
+
+        shard.flush_backlogs();
+        assert_eq!(drain(&mut receiver).await, vec!["3"]);
+
+        shard.flush_backlogs();
+        assert_eq!(drain(&mut receiver).await, vec!["4"]);
+        assert!(shard.backlogs.is_empty());
+        assert_eq!(shard.delivered, 3);
+        assert!(shard.is_online(&channel));
+    }
+
+    #[actix_web::test]
+    async fn test_disconnect() {
+        let channel = Channel::create_private("abc".to_string());
+        let (client, mut receiver) = slow_subscriber();
+        let mut shard = shard(SlowConsumerPolicy::Disconnect, 2);
+
+        shard.add_client_to_channel(channel.clone(), client);
+        publish(&mut shard, &channel, &["1", "2", "3"]);
+
+        assert_eq!(shard.disconnected, 1);
+        assert!(!shard.is_online(&channel));
+        assert_eq!(drain(&mut receiver).await, vec!["1", "disconnect"]);
+    }
+
+    #[actix_web::test]
+    async fn test_buffer_then_disconnect() {
+        let channel = Channel::create_private("abc".to_string());
+        let (client, mut receiver) = slow_subscriber();
+        let mut shard = shard(SlowConsumerPolicy::Buffer, 2);
+
+        shard.add_client_to_channel(channel.clone(), client);
+        publish(&mut shard, &channel, &["1", "2", "3"]);
+
+        assert_eq!(shard.disconnected, 0);
+        assert_eq!(drain(&mut receiver).await, vec!["1"]);
+
+        publish(&mut shard, &channel, &["4", "5"]);
+
+        assert_eq!(shard.disconnected, 1);
+        assert_eq!(shard.dropped, 2);
+        assert!(shard.backlogs.is_empty());
+        assert_eq!(drain(&mut receiver).await, vec!["disconnect"]);
+    }
+
+    #[actix_web::test]
+    async fn test_replay_waits_in_backlog() {
+        let (live, replayed) = (Channel::create_private("abc".to_string()), Channel::create_private("def".to_string()));
+        let (client, mut receiver) = slow_subscriber();
+        let mut shard = WsPullShard::new(
+            Backpressure { policy: SlowConsumerPolicy::Buffer, buffer_size: 2 },
+            History::new(10, 10),
+        );
+
+        for (sequence, body) in (1..).zip(["1", "2", "3"]) {
+            shard.remember(std::slice::from_ref(&replayed), &message(body).with_sequence(sequence));
+        }
+
+        shard.add_client_to_channel(live.clone(), client.clone());
+        publish(&mut shard, &live, &["1", "2"]);
+
+        shard.add_client_to_channel(replayed.clone(), client.clone());
+        shard.replay(&[replayed], &client, 1);
+
+        /* Behind the live message the mailbox had no room for */
+        assert_eq!(shard.backlogs.get(&client).map(VecDeque::len), Some(2));
+        assert_eq!(drain(&mut receiver).await, vec!["1"]);
+
+        let mut flushed = Vec::new();
+        for _ in 0..2 {
+            shard.flush_backlogs();
+            flushed.extend(drain(&mut receiver).await);
+        }
+
+        assert_eq!(flushed, vec!["2", "23"]);
+    }
+
+    #[actix_web::test]
+    async fn test_unsubscribe_removes_client() {
+        let channel = Channel::create_private("abc".to_string());
+        let (client, mut receiver) = slow_subscriber();
+        let (other, mut other_receiver) = slow_subscriber();
+        let shard = shard(SlowConsumerPolicy::Buffer, 2).start();
+
+        shard
+            .send(SubscribeChannelMessage(vec![channel.clone()], client.clone(), None))
+            .await
+            .unwrap();
+        shard
+            .send(SubscribeChannelMessage(vec![channel.clone()], other.clone(), None))
+            .await
+            .unwrap();
+        shard
+            .send(UnsubscribeChannelMessage(vec![channel.clone()], client))
+            .await
+            .unwrap();
+        shard.send(SendPullMessage(vec![channel.clone()], message("1").with_sequence(1))).await.unwrap();
+
+        assert!(drain(&mut receiver).await.is_empty());
+        assert_eq!(drain(&mut other_receiver).await, vec!["1"]);
+        assert_eq!(shard.send(ShardStatsMessage).await.unwrap().subscribers, 1);
+
+        shard
+            .send(UnsubscribeChannelMessage(vec![channel.clone()], other))
+            .await
+            .unwrap();
+
+        let stats = shard.send(ShardStatsMessage).await.unwrap();
+
+        assert_eq!(stats.channels, 0);
+        assert_eq!(shard.send(ChannelStatsMessage(vec![channel])).await.unwrap(), vec![false]);
+    }
+}
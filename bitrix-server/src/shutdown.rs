@@ -0,0 +1,56 @@
+use std::future::Future;
+use std::io;
+
+use actix::SystemService;
+use actix_web::dev::ServerHandle;
+use actix_web_actors::ws::{CloseCode, CloseReason};
+
+use crate::{message::ShutdownMessage, server::WsPullServer};
+
+/// Stop gracefully on SIGTERM or SIGINT.
+///
+/// Sessions get a close frame first, then the HTTP server stops accepting
+/// and waits its `shutdown_timeout` for connections to finish.
+pub fn on_signal(server: ServerHandle) -> io::Result<()> {
+    let terminate = terminate_signal()?;
+
+    actix::spawn(async move {
+        terminate.await;
+
+        log::info!("shutdown requested, closing sessions");
+
+        let reason = CloseReason {
+            code: CloseCode::Away,
+            description: Some("Server is shutting down".to_string()),
+        };
+
+        match WsPullServer::from_registry().send(ShutdownMessage(reason)).await {
+            Ok(sessions) => log::info!("{sessions} sessions asked to close"),
+            Err(error) => log::error!("Couldn't close sessions: {error}"),
+        }
+
+        server.stop(true).await;
+    });
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn terminate_signal() -> io::Result<impl Future<Output = ()>> {
+    use futures_util::future::select;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    Ok(async move {
+        select(Box::pin(terminate.recv()), Box::pin(interrupt.recv())).await;
+    })
+}
+
+#[cfg(not(unix))]
+fn terminate_signal() -> io::Result<impl Future<Output = ()>> {
+    Ok(async {
+        let _ = tokio::signal::ctrl_c().await;
+    })
+}
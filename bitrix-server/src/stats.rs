@@ -0,0 +1,53 @@
+use serde::Serialize;
+
+use crate::message::SessionKind;
+
+/// Message counters since the server start
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct MessageStats {
+    pub published: u64,
+    pub delivered: u64,
+    pub failed: u64,
+    /// Thrown away by the slow consumer policy
+    pub dropped: u64,
+    /// Subscribers disconnected by the slow consumer policy
+    pub disconnected: u64,
+}
+
+/// Payload of `/server-stat/` and of the binary `ServerStats` command
+#[derive(Serialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerStats {
+    pub pid: u32,
+    pub date: u32,
+    pub clients: usize,
+    pub channels: usize,
+    /// Subscriptions summed over the channels, channel ids are never exposed
+    pub subscribers: usize,
+    pub messages: MessageStats,
+}
+
+/// One subscriber in the admin API
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionStats {
+    pub id: String,
+    pub kind: SessionKind,
+    pub channels: Vec<String>,
+    /// Unix time of the subscription
+    pub connected: u32,
+    pub remote_addr: Option<String>,
+    pub sent: u64,
+}
+
+/// Slice of `ServerStats` one `WsPullShard` knows about
+#[derive(Debug, Default, Clone)]
+pub struct ShardStats {
+    /// Channels with at least one connected subscriber
+    pub channels: usize,
+    pub subscribers: usize,
+    pub delivered: u64,
+    pub failed: u64,
+    pub dropped: u64,
+    pub disconnected: u64,
+}
@@ -0,0 +1,226 @@
+use std::fmt;
+use std::io;
+use std::sync::{Arc, RwLock};
+
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    RootCertStore, ServerConfig,
+};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+use crate::settings;
+
+/// Server certificate, swapped in place on reload.
+///
+/// Connections being established take the current certificate, the ones
+/// already running keep theirs.
+pub struct CertificateStore {
+    settings: settings::Tls,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertificateStore {
+    pub fn load(settings: settings::Tls, provider: Arc<CryptoProvider>) -> io::Result<Self> {
+        let current = load_certified_key(&settings, &provider)?;
+
+        Ok(CertificateStore {
+            settings,
+            provider,
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    /// Read certificate and key files again, the old pair stays if they are broken
+    pub fn reload(&self) -> io::Result<()> {
+        let certified_key = load_certified_key(&self.settings, &self.provider)?;
+
+        *self.current.write().unwrap() = Arc::new(certified_key);
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for CertificateStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertificateStore")
+            .field("cert_path", &self.settings.cert_path)
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for CertificateStore {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// rustls config for `[tls]`, with the store to reload certificates
+pub fn server_config(settings: &settings::Tls) -> io::Result<(ServerConfig, Arc<CertificateStore>)> {
+    let provider = Arc::new(ring::default_provider());
+    let store = Arc::new(CertificateStore::load(settings.clone(), provider.clone())?);
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?;
+
+    let builder = match &settings.client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+
+            for certificate in load_certificates(client_ca_path)? {
+                roots.add(certificate).map_err(invalid_data)?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(invalid_data)?;
+
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok((builder.with_cert_resolver(store.clone()), store))
+}
+
+/// Reload certificates every time the process gets SIGHUP
+#[cfg(unix)]
+pub fn reload_on_sighup(store: Arc<CertificateStore>) -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    actix::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match store.reload() {
+                Ok(()) => log::info!("TLS certificates reloaded from {}", store.settings.cert_path),
+                Err(error) => log::error!("TLS certificates reload failed, keep the old ones: {error}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn reload_on_sighup(_store: Arc<CertificateStore>) -> io::Result<()> {
+    log::warn!("TLS certificates reload on SIGHUP is not supported on this platform");
+    Ok(())
+}
+
+fn load_certified_key(settings: &settings::Tls, provider: &CryptoProvider) -> io::Result<CertifiedKey> {
+    let certificates = load_certificates(&settings.cert_path)?;
+
+    let key = PrivateKeyDer::from_pem_file(&settings.key_path)
+        .map_err(|error| invalid_data(format!("{}: {error}", settings.key_path)))?;
+
+    CertifiedKey::from_der(certificates, key, provider).map_err(invalid_data)
+}
+
+fn load_certificates(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|error| invalid_data(format!("{path}: {error}")))?;
+
+    if certificates.is_empty() {
+        return Err(invalid_data(format!("{path}: no certificates found")));
+    }
+
+    Ok(certificates)
+}
+
+fn invalid_data(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/testdata/{name}", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    fn tls_settings(name: &str) -> settings::Tls {
+        settings::Tls {
+            cert_path: testdata(&format!("{name}.cert.pem")),
+            key_path: testdata(&format!("{name}.key.pem")),
+            client_ca_path: None,
+        }
+    }
+
+    fn current_certificate(store: &CertificateStore) -> CertificateDer<'static> {
+        store.current.read().unwrap().cert[0].clone()
+    }
+
+    #[test]
+    fn test_server_config() {
+        assert!(server_config(&tls_settings("first")).is_ok());
+    }
+
+    #[test]
+    fn test_server_config_with_client_ca() {
+        let settings = settings::Tls {
+            client_ca_path: Some(testdata("second.cert.pem")),
+            ..tls_settings("first")
+        };
+
+        assert!(server_config(&settings).is_ok());
+    }
+
+    #[test]
+    fn test_missing_files() {
+        let settings = settings::Tls {
+            cert_path: testdata("missing.pem"),
+            ..tls_settings("first")
+        };
+
+        assert_eq!(server_config(&settings).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_key_must_match_certificate() {
+        let settings = settings::Tls {
+            key_path: testdata("second.key.pem"),
+            ..tls_settings("first")
+        };
+
+        assert!(server_config(&settings).is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let directory = std::env::temp_dir().join(format!("push-server-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let settings = settings::Tls {
+            cert_path: directory.join("cert.pem").to_string_lossy().into_owned(),
+            key_path: directory.join("key.pem").to_string_lossy().into_owned(),
+            client_ca_path: None,
+        };
+        let install = |name: &str| {
+            std::fs::copy(testdata(&format!("{name}.cert.pem")), &settings.cert_path).unwrap();
+            std::fs::copy(testdata(&format!("{name}.key.pem")), &settings.key_path).unwrap();
+        };
+
+        install("first");
+        let (_, store) = server_config(&settings).unwrap();
+        let first = current_certificate(&store);
+
+        install("second");
+        store.reload().unwrap();
+        let second = current_certificate(&store);
+
+        assert_ne!(first, second);
+
+        std::fs::write(&settings.key_path, "broken").unwrap();
+
+        assert!(store.reload().is_err());
+        assert_eq!(current_certificate(&store), second);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -1,4 +1,7 @@
 use rand::{thread_rng, Rng};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::items;
 
 pub fn get_message_id() -> Vec<u8> {
     (0..16)
@@ -6,6 +9,93 @@ pub fn get_message_id() -> Vec<u8> {
         .collect::<Vec<u8>>()
 }
 
+pub fn encode_message_id(id: &[u8]) -> String {
+    encode_hex(id)
+}
+
+pub fn decode_message_id(mid: &str) -> Option<Vec<u8>> {
+    decode_hex(mid).filter(|id| !id.is_empty())
+}
+
+/// Lowercase hex, two digits per byte
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Strict hex of either case, `None` on odd length or anything but hex digits
+pub fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    fn digit(byte: u8) -> Option<u8> {
+        match byte {
+            b'0'..=b'9' => Some(byte - b'0'),
+            b'a'..=b'f' => Some(byte - b'a' + 10),
+            b'A'..=b'F' => Some(byte - b'A' + 10),
+            _ => None,
+        }
+    }
+
+    let text = text.as_bytes();
+
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+
+    text.chunks(2)
+        .map(|pair| Some(digit(pair[0])? << 4 | digit(pair[1])?))
+        .collect()
+}
+
+/// Current unix time in seconds, as used by `OutgoingMessage.created`
+pub fn get_timestamp() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as u32)
+        .unwrap_or_default()
+}
+
+/// `expiry` is a lifetime in seconds counted from `created`, zero means forever
+pub fn is_expired(message: &items::OutgoingMessage, now: u32) -> bool {
+    message.expiry != 0 && message.created.saturating_add(message.expiry) <= now
+}
+
+/// Remove expired messages from the batch, returns `false` if nothing is left to deliver
+pub fn drop_expired(batch: &mut items::ResponseBatch, now: u32) -> bool {
+    retain_messages(batch, |message| !is_expired(message, now))
+}
+
+/// Keep only outgoing messages matching `keep`, returns `false` if nothing is left to deliver
+pub fn retain_messages(
+    batch: &mut items::ResponseBatch,
+    keep: impl Fn(&items::OutgoingMessage) -> bool,
+) -> bool {
+    for response in batch.responses.iter_mut() {
+        if let Some(items::response::Command::OutgoingMessages(outgoing)) = response.command.as_mut() {
+            outgoing.messages.retain(&keep);
+        }
+    }
+
+    batch.responses.retain(|response| match &response.command {
+        Some(items::response::Command::OutgoingMessages(outgoing)) => !outgoing.messages.is_empty(),
+        Some(_) => true,
+        None => false,
+    });
+
+    !batch.responses.is_empty()
+}
+
+/// Every outgoing message of the batch, in order
+pub fn get_outgoing_messages(
+    batch: &items::ResponseBatch,
+) -> impl DoubleEndedIterator<Item = &items::OutgoingMessage> {
+    batch
+        .responses
+        .iter()
+        .filter_map(|response| match &response.command {
+            Some(items::response::Command::OutgoingMessages(outgoing)) => Some(&outgoing.messages),
+            _ => None,
+        })
+        .flatten()
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -25,4 +115,100 @@ mod tests {
 
         assert!(parse_result_1 != parse_result_2);
     }
+
+    #[actix_web::test]
+    async fn test_encode_message_id() {
+        let encoded = encode_message_id(&[0, 15, 16, 255]);
+
+        assert_eq!(encoded, "000f10ff".to_string());
+    }
+
+    #[actix_web::test]
+    async fn test_decode_message_id() {
+        let message_id = get_message_id();
+
+        let decoded = decode_message_id(&encode_message_id(&message_id));
+
+        assert_eq!(decoded, Some(message_id));
+    }
+
+    #[actix_web::test]
+    async fn test_decode_broken_message_id() {
+        assert_eq!(decode_message_id(""), None);
+        assert_eq!(decode_message_id("abc"), None);
+        assert_eq!(decode_message_id("zz"), None);
+    }
+
+    #[actix_web::test]
+    async fn test_hex() {
+        assert_eq!(encode_hex(&[0, 15, 16, 255]), "000f10ff");
+        assert_eq!(decode_hex("000f10FF"), Some(vec![0, 15, 16, 255]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+
+        assert_eq!(decode_hex("+a"), None);
+        assert_eq!(decode_hex("-1"), None);
+        assert_eq!(decode_hex("a"), None);
+        assert_eq!(decode_hex("0g"), None);
+        assert_eq!(decode_hex("é0"), None);
+    }
+
+    #[actix_web::test]
+    async fn test_message_without_expiry_never_expires() {
+        let message = items::OutgoingMessage {
+            created: 100,
+            expiry: 0,
+            ..Default::default()
+        };
+
+        assert!(!is_expired(&message, u32::MAX));
+    }
+
+    #[actix_web::test]
+    async fn test_message_expires_after_lifetime() {
+        let message = items::OutgoingMessage {
+            created: 100,
+            expiry: 10,
+            ..Default::default()
+        };
+
+        assert!(!is_expired(&message, 109));
+        assert!(is_expired(&message, 110));
+    }
+
+    #[actix_web::test]
+    async fn test_drop_expired_from_batch() {
+        let message = |expiry| items::OutgoingMessage {
+            created: 100,
+            expiry,
+            ..Default::default()
+        };
+
+        let mut batch = items::ResponseBatch {
+            responses: vec![
+                items::Response {
+                    command: Some(items::response::Command::OutgoingMessages(
+                        items::OutgoingMessagesResponse { messages: vec![message(1), message(0)] },
+                    )),
+                },
+                items::Response {
+                    command: Some(items::response::Command::OutgoingMessages(
+                        items::OutgoingMessagesResponse { messages: vec![message(1)] },
+                    )),
+                },
+            ],
+        };
+
+        assert!(drop_expired(&mut batch, 200));
+        assert_eq!(batch.responses.len(), 1);
+
+        let mut expired_batch = items::ResponseBatch {
+            responses: vec![items::Response {
+                command: Some(items::response::Command::OutgoingMessages(
+                    items::OutgoingMessagesResponse { messages: vec![message(1)] },
+                )),
+            }],
+        };
+
+        assert!(!drop_expired(&mut expired_batch, 200));
+    }
 }